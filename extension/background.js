@@ -2,15 +2,27 @@
 var port = null;
 var listeners = /* @__PURE__ */ new Set();
 var disconnectListeners = /* @__PURE__ */ new Set();
+var nextRequestId = 1;
+var pendingRequests = /* @__PURE__ */ new Map();
 function openOrReusePort() {
   if (!port) {
     port = chrome.runtime.connectNative("f_browser_helper_app");
     port.onMessage.addListener((msg) => {
+      const resolve = msg.id !== void 0 ? pendingRequests.get(msg.id) : void 0;
+      if (resolve) {
+        pendingRequests.delete(msg.id);
+        resolve(msg);
+        return;
+      }
       for (const listener of listeners) {
         listener(msg);
       }
     });
     port.onDisconnect.addListener((port2) => {
+      for (const resolve of pendingRequests.values()) {
+        resolve({ type: "error", message: "Disconnected from native app" });
+      }
+      pendingRequests.clear();
       for (const listener of disconnectListeners) {
         listener(port2);
       }
@@ -21,18 +33,30 @@ function openOrReusePort() {
 function postMessage(msg) {
   try {
     openOrReusePort()?.postMessage(msg);
+    return true;
   } catch (e) {
     console.warn("Error posting message: ", e);
     port = null;
+    return false;
   }
 }
+function request(msg) {
+  const id = nextRequestId++;
+  return new Promise((resolve) => {
+    pendingRequests.set(id, resolve);
+    if (!postMessage({ ...msg, id })) {
+      pendingRequests.delete(id);
+      resolve({ id, type: "error", message: "Failed to post message" });
+    }
+  });
+}
 function listenToMessage(cb) {
   listeners.add(cb);
-  openOrReusePort()?.onMessage.addListener(cb);
+  openOrReusePort();
 }
 function listenToDisconnect(cb) {
   disconnectListeners.add(cb);
-  openOrReusePort()?.onDisconnect.addListener(cb);
+  openOrReusePort();
 }
 
 // extension/background.ts
@@ -51,7 +75,8 @@ function updateWindowIcon(tab) {
   postMessage({
     type: "setTaskbarIcon",
     hwnd: windowInfo.hwnd,
-    iconUrl: tab.url
+    iconUrl: tab.url,
+    faviconUrl: tab.favIconUrl
   });
 }
 chrome.tabs.onActivated.addListener(async (activeInfo) => {
@@ -67,17 +92,45 @@ chrome.tabs.onUpdated.addListener(async (tabId, changeInfo, tab) => {
     }
   }
 });
-var curWindowId = null;
+async function storeActiveWindow(windowId) {
+  const msg = await request({
+    type: "getActiveWindow"
+  });
+  if (msg.type !== "activeWindow") {
+    console.warn("Failed to get active window: ", msg);
+    return;
+  }
+  if (!msg.isBrowser) {
+    return;
+  }
+  console.log("Active window: ", msg, windowId);
+  if (windowInfoMap.has(windowId)) {
+    return;
+  }
+  windowInfoMap.set(windowId, {
+    hwnd: msg.hwnd,
+    className: msg.className
+  });
+  postMessage({
+    type: "ungroupTaskbarButton",
+    hwnd: msg.hwnd,
+    newId: windowId.toString()
+  });
+  const tabs = await chrome.tabs.query({
+    active: true,
+    windowId
+  });
+  if (tabs.length > 0) {
+    updateWindowIcon(tabs[0]);
+  }
+}
 chrome.windows.onFocusChanged.addListener(async (windowId) => {
   console.log("Focus changed: ", windowId);
   if (windowId === chrome.windows.WINDOW_ID_NONE || windowId === chrome.windows.WINDOW_ID_CURRENT) {
     return;
   }
-  curWindowId = windowId;
   if (!windowInfoMap.has(windowId)) {
-    postMessage({
-      type: "getActiveWindow"
-    });
+    storeActiveWindow(windowId);
   }
   const tabs = await chrome.tabs.query({
     active: true,
@@ -92,41 +145,16 @@ chrome.tabs.onDetached.addListener(async (tabId) => {
   const tab = await chrome.tabs.get(tabId);
   updateWindowIcon(tab);
 });
-listenToMessage(async (msg) => {
-  if (msg.type === "activeWindow") {
-    if (!curWindowId || curWindowId === -1) {
-      return;
-    }
-    if (!msg.processName.endsWith("chrome.exe") && !msg.processName.endsWith("msedge.exe") && !msg.processName.endsWith("firefox.exe")) {
-      return;
-    }
-    console.log("Active window: ", msg, curWindowId);
-    if (windowInfoMap.has(curWindowId)) {
-      return;
-    }
-    windowInfoMap.set(curWindowId, {
-      hwnd: msg.hwnd,
-      className: msg.className
-    });
-    postMessage({
-      type: "ungroupTaskbarButton",
-      hwnd: msg.hwnd,
-      newId: curWindowId.toString()
-    });
-    const tabs = await chrome.tabs.query({
-      active: true,
-      windowId: curWindowId
-    });
-    if (tabs.length > 0) {
-      updateWindowIcon(tabs[0]);
-    }
-  }
-});
 listenToDisconnect(() => {
   console.log("Disconnected from native app.");
 });
-chrome.action.onClicked.addListener(() => {
-  postMessage({
-    type: "getActiveWindow"
-  });
+listenToMessage((msg) => {
+  if (msg.type === "configError") {
+    console.warn("Native app config ignored: ", msg.message);
+  }
+});
+chrome.action.onClicked.addListener((tab) => {
+  if (tab.windowId) {
+    storeActiveWindow(tab.windowId);
+  }
 });
@@ -1,6 +1,6 @@
 import type {} from "npm:@types/chrome";
 // import type { Browser, Runtime, Tabs } from "npm:@types/webextension-polyfill";
-import { postMessage, listenToMessage, listenToDisconnect } from "./messaging.ts";
+import { postMessage, request, listenToDisconnect, listenToMessage } from "./messaging.ts";
 import { PortableLoader } from "https://deno.land/x/esbuild_deno_loader@0.9.0/src/loader_portable.ts";
 
 // declare const browser: Browser;
@@ -14,6 +14,7 @@ type WindowInfo = {
 };
 
 const windowInfoMap = new Map<WindowId, WindowInfo>();
+
 const taskbarButtonGroups = new Map<string, WindowId[]>();
 
 /*
@@ -44,6 +45,7 @@ function updateWindowIcon(tab: chrome.tabs.Tab) {
         type: "setTaskbarIcon",
         hwnd: windowInfo.hwnd,
         iconUrl: tab.url,
+        faviconUrl: tab.favIconUrl,
     });
 }
 
@@ -71,7 +73,51 @@ chrome.tabs.onUpdated.addListener(async (tabId, changeInfo, tab) => {
 //     });
 // });
 
-let curWindowId: WindowId | null = null;
+/**
+ * Ask the native app for the active window and store it as the given window
+ */
+async function storeActiveWindow(windowId: WindowId) {
+    const msg = await request({
+        type: "getActiveWindow",
+    });
+    if (msg.type !== "activeWindow") {
+        console.warn("Failed to get active window: ", msg);
+        return;
+    }
+
+    // Ignore non-browser windows, the native app knows the browser processes.
+    if (!msg.isBrowser) {
+        return;
+    }
+
+    console.log("Active window: ", msg, windowId);
+
+    // Ignore if the window is already stored.
+    if (windowInfoMap.has(windowId)) {
+        return;
+    }
+
+    windowInfoMap.set(windowId, {
+        hwnd: msg.hwnd,
+        className: msg.className,
+    });
+
+    postMessage({
+        type: "ungroupTaskbarButton",
+        hwnd: msg.hwnd,
+        newId: windowId.toString(),
+    });
+
+    const tabs = await chrome.tabs.query({
+        active: true,
+        windowId: windowId,
+    });
+
+    if (tabs.length > 0) {
+        updateWindowIcon(tabs[0]);
+    }
+}
+
 chrome.windows.onFocusChanged.addListener(async (windowId) => {
     console.log("Focus changed: ", windowId);
     // If windowId is -1, it means no browser window is focused.
@@ -82,14 +128,9 @@ chrome.windows.onFocusChanged.addListener(async (windowId) => {
         return;
     }
 
-    // Store the current windowId.
-    curWindowId = windowId;
-
     // If we don't have window info, request it.
     if (!windowInfoMap.has(windowId)) {
-        postMessage({
-            type: "getActiveWindow",
-        });
+        storeActiveWindow(windowId);
     }
 
     // Update icon of active tab in the window
@@ -109,58 +150,19 @@ chrome.tabs.onDetached.addListener(async (tabId) => {
     updateWindowIcon(tab);
 });
 
-listenToMessage(async (msg) => {
-    if (msg.type === "activeWindow") {
-        // Ignore if no window is focused.
-        if (!curWindowId || curWindowId === -1) {
-            return;
-        }
-
-        // Ignore non-browser windows.
-        if (
-            !msg.processName.endsWith("chrome.exe") &&
-            !msg.processName.endsWith("msedge.exe") &&
-            !msg.processName.endsWith("firefox.exe")
-        ) {
-            return;
-        }
-
-        console.log("Active window: ", msg, curWindowId);
-
-        // Ignore if the window is already stored.
-        if (windowInfoMap.has(curWindowId)) {
-            return;
-        }
-
-        windowInfoMap.set(curWindowId, {
-            hwnd: msg.hwnd,
-            className: msg.className,
-        });
-
-        postMessage({
-            type: "ungroupTaskbarButton",
-            hwnd: msg.hwnd,
-            newId: curWindowId.toString(),
-        });
-
-        const tabs = await chrome.tabs.query({
-            active: true,
-            windowId: curWindowId,
-        });
-
-        if (tabs.length > 0) {
-            updateWindowIcon(tabs[0]);
-        }
-    }
-});
-
 listenToDisconnect(() => {
     console.log("Disconnected from native app.");
 });
 
+listenToMessage((msg) => {
+    if (msg.type === "configError") {
+        console.warn("Native app config ignored: ", msg.message);
+    }
+});
+
 // Browser action
-chrome.action.onClicked.addListener(() => {
-    postMessage({
-        type: "getActiveWindow",
-    });
+chrome.action.onClicked.addListener((tab) => {
+    if (tab.windowId) {
+        storeActiveWindow(tab.windowId);
+    }
 });
@@ -2,15 +2,27 @@
 var port = null;
 var listeners = /* @__PURE__ */ new Set();
 var disconnectListeners = /* @__PURE__ */ new Set();
+var nextRequestId = 1;
+var pendingRequests = /* @__PURE__ */ new Map();
 function openOrReusePort() {
   if (!port) {
     port = chrome.runtime.connectNative("f_browser_helper_app");
     port.onMessage.addListener((msg) => {
+      const resolve = msg.id !== void 0 ? pendingRequests.get(msg.id) : void 0;
+      if (resolve) {
+        pendingRequests.delete(msg.id);
+        resolve(msg);
+        return;
+      }
       for (const listener of listeners) {
         listener(msg);
       }
     });
     port.onDisconnect.addListener((port2) => {
+      for (const resolve of pendingRequests.values()) {
+        resolve({ type: "error", message: "Disconnected from native app" });
+      }
+      pendingRequests.clear();
       for (const listener of disconnectListeners) {
         listener(port2);
       }
@@ -21,21 +33,34 @@ function openOrReusePort() {
 function postMessage(msg) {
   try {
     openOrReusePort()?.postMessage(msg);
+    return true;
   } catch (e) {
     console.warn("Error posting message: ", e);
     port = null;
+    return false;
   }
 }
+function request(msg) {
+  const id = nextRequestId++;
+  return new Promise((resolve) => {
+    pendingRequests.set(id, resolve);
+    if (!postMessage({ ...msg, id })) {
+      pendingRequests.delete(id);
+      resolve({ id, type: "error", message: "Failed to post message" });
+    }
+  });
+}
 function listenToMessage(cb) {
   listeners.add(cb);
-  openOrReusePort()?.onMessage.addListener(cb);
+  openOrReusePort();
 }
 function listenToDisconnect(cb) {
   disconnectListeners.add(cb);
-  openOrReusePort()?.onDisconnect.addListener(cb);
+  openOrReusePort();
 }
 export {
   listenToDisconnect,
   listenToMessage,
-  postMessage
+  postMessage,
+  request
 };
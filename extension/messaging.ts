@@ -7,33 +7,67 @@ declare const chrome: typeof globalThis.chrome;
 type MessageFromBrowser =
     | { type: "getActiveWindow" }
     | { type: "ungroupTaskbarButton"; hwnd: number; newId: string }
-    | { type: "setTaskbarIcon"; hwnd: number; iconUrl?: string }
+    | {
+          type: "setTaskbarIcon";
+          hwnd: number;
+          iconUrl?: string;
+          faviconUrl?: string;
+          iconData?: string;
+          mimeType?: string;
+      }
     | { type: "quit" };
 
 type MessageToBrowser =
-    | { type: "activeWindow"; hwnd: number; className: string; processName: string; title: string }
-    | { type: "ok" };
+    | {
+          type: "activeWindow";
+          hwnd: number;
+          className: string;
+          processName: string;
+          title: string;
+          isBrowser: boolean;
+      }
+    | { type: "ok" }
+    | { type: "superseded" };
 
 type MessageToError =
     | { type: "urlParsingError"; message: string }
     | { type: "error"; message: string }
     | { type: "ioError"; kind: string; message: string }
     | { type: "jsonParseError"; message: string }
+    | { type: "messageTooLarge"; length: number; maxLength: number }
+    | { type: "configError"; message: string }
     | { type: "panic"; message: string; file: string | null; line: number | null };
 
+type RequestId = number;
+type Response = (MessageToBrowser | MessageToError) & { id?: RequestId };
+
 let port: chrome.runtime.Port | null = null;
-let listeners = new Set<(msg: MessageToBrowser | MessageToError) => void>();
+let listeners = new Set<(msg: Response) => void>();
 let disconnectListeners = new Set<(port: chrome.runtime.Port) => void>();
 
+// Requests waiting for a response with the same id
+let nextRequestId: RequestId = 1;
+const pendingRequests = new Map<RequestId, (msg: Response) => void>();
+
 function openOrReusePort() {
     if (!port) {
         port = chrome.runtime.connectNative("f_browser_helper_app");
-        port.onMessage.addListener((msg) => {
+        port.onMessage.addListener((msg: Response) => {
+            const resolve = msg.id !== undefined ? pendingRequests.get(msg.id) : undefined;
+            if (resolve) {
+                pendingRequests.delete(msg.id!);
+                resolve(msg);
+                return;
+            }
             for (const listener of listeners) {
                 listener(msg);
             }
         });
         port.onDisconnect.addListener((port) => {
+            for (const resolve of pendingRequests.values()) {
+                resolve({ type: "error", message: "Disconnected from native app" });
+            }
+            pendingRequests.clear();
             for (const listener of disconnectListeners) {
                 listener(port);
             }
@@ -42,21 +76,37 @@ function openOrReusePort() {
     return port;
 }
 
-export function postMessage(msg: MessageFromBrowser) {
+export function postMessage(msg: MessageFromBrowser & { id?: RequestId }) {
     try {
         openOrReusePort()?.postMessage(msg);
+        return true;
     } catch (e) {
         console.warn("Error posting message: ", e);
         port = null;
+        return false;
     }
 }
 
-export function listenToMessage(cb: (msg: MessageToBrowser | MessageToError) => void) {
+/**
+ * Post a message and wait for the response with the same id
+ */
+export function request(msg: MessageFromBrowser): Promise<Response> {
+    const id = nextRequestId++;
+    return new Promise((resolve) => {
+        pendingRequests.set(id, resolve);
+        if (!postMessage({ ...msg, id })) {
+            pendingRequests.delete(id);
+            resolve({ id, type: "error", message: "Failed to post message" });
+        }
+    });
+}
+
+export function listenToMessage(cb: (msg: Response) => void) {
     listeners.add(cb);
-    openOrReusePort()?.onMessage.addListener(cb);
+    openOrReusePort();
 }
 
 export function listenToDisconnect(cb: (port: chrome.runtime.Port) => void) {
     disconnectListeners.add(cb);
-    openOrReusePort()?.onDisconnect.addListener(cb);
+    openOrReusePort();
 }
@@ -1,16 +1,23 @@
-use std::panic;
+use std::cell::RefCell;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, VecDeque};
+use std::io::{Read, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{mpsc, Mutex};
+use std::thread;
 
+use base64::Engine;
 use serde::{Deserialize, Serialize};
-use windows::Win32::Foundation::HWND;
 
 use crate::log;
-use crate::utils::favicon::get_favicon_from_url;
-use crate::utils::native_messaging::{read_message, send_message};
-use crate::utils::win32::{
-    allow_maximize_and_snapping, clear_pinned_taskbar_icon, get_active_window, get_process_name,
-    get_window_class, get_window_title, prevent_pinning_taskbar_button, set_icon,
-    ungroup_taskbar_button,
+use crate::utils::config::BrowserConfig;
+use crate::utils::favicon::FaviconProviders;
+use crate::utils::favicon_provider::decode_data_url;
+use crate::utils::log::{log_at, Level};
+use crate::utils::native_messaging::{
+    read_message, send_message, Envelope, FrameError, MessageError, NativeMessagingCodec,
 };
+use crate::utils::window_manager::WindowManager;
 
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(
@@ -20,11 +27,38 @@ use crate::utils::win32::{
 )]
 pub enum MessageFromBrowser {
     GetActiveWindow,
-    UngroupTaskbarButton { hwnd: u32, new_id: String },
-    SetTaskbarIcon { hwnd: u32, icon_url: String },
+    UngroupTaskbarButton {
+        hwnd: u32,
+        new_id: String,
+    },
+    /// Icon is taken from `icon_data` if given, otherwise from `icon_url`,
+    /// which can be also a `data:` URL
+    SetTaskbarIcon {
+        hwnd: u32,
+        #[serde(default)]
+        icon_url: String,
+        #[serde(default)]
+        favicon_url: Option<String>,
+        /// Base64 encoded image bytes
+        #[serde(default)]
+        icon_data: Option<String>,
+        #[serde(default)]
+        mime_type: Option<String>,
+    },
     Quit,
 }
 
+impl MessageFromBrowser {
+    /// Window the request changes
+    fn hwnd(&self) -> Option<u32> {
+        match self {
+            MessageFromBrowser::UngroupTaskbarButton { hwnd, .. }
+            | MessageFromBrowser::SetTaskbarIcon { hwnd, .. } => Some(*hwnd),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(
     tag = "type",
@@ -37,8 +71,12 @@ pub enum MessageToBrowser {
         class_name: String,
         title: String,
         process_name: String,
+        /// Process is one of the configured browsers
+        is_browser: bool,
     },
     Ok,
+    /// Request was skipped because a newer one for the same window arrived
+    Superseded,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -61,6 +99,15 @@ pub enum MessageToError {
     JsonParseError {
         message: String,
     },
+    MessageTooLarge {
+        length: u32,
+        max_length: u32,
+    },
+    /// Config file could not be used, sent at startup before the defaults
+    /// are used instead
+    ConfigError {
+        message: String,
+    },
     Panic {
         message: String,
         file: Option<String>,
@@ -69,53 +116,117 @@ pub enum MessageToError {
     Quit,
 }
 
-fn event_handler(msg: MessageFromBrowser) -> Result<MessageToBrowser, MessageToError> {
+// Allow MessageError to be converted to MessageToError
+impl From<&MessageError> for MessageToError {
+    fn from(err: &MessageError) -> Self {
+        match err {
+            MessageError::Closed => MessageToError::IoError {
+                kind: std::io::ErrorKind::UnexpectedEof.to_string(),
+                message: "Input closed".into(),
+            },
+            MessageError::Io(err) => MessageToError::IoError {
+                kind: err.kind().to_string(),
+                message: format!("{}", err),
+            },
+            MessageError::TooLarge { length, max_length } => MessageToError::MessageTooLarge {
+                length: *length,
+                max_length: *max_length,
+            },
+            MessageError::Json { error, .. } => MessageToError::JsonParseError {
+                message: format!("{}", error),
+            },
+        }
+    }
+}
+
+/// Handle a message from the browser
+///
+/// `is_superseded` tells whether a newer icon request for the same window has
+/// arrived, the icon is not set then.
+fn event_handler(
+    wm: &impl WindowManager,
+    favicons: &FaviconProviders,
+    browser: &BrowserConfig,
+    msg: MessageFromBrowser,
+    is_superseded: &dyn Fn() -> bool,
+) -> Result<MessageToBrowser, MessageToError> {
     match msg {
         MessageFromBrowser::GetActiveWindow => {
-            let nhwnd = get_active_window();
-            let class_name = get_window_class(nhwnd);
-            let process_name = get_process_name(nhwnd);
-            let title = get_window_title(nhwnd);
-            let hwnd = nhwnd.0 as u32;
+            let hwnd = wm.get_active_window();
+            let class_name = wm.get_window_class(hwnd);
+            let process_name = wm.get_process_name(hwnd);
+            let title = wm.get_window_title(hwnd);
+            let is_browser = browser.is_browser_process(&process_name);
 
             Ok(MessageToBrowser::ActiveWindow {
                 hwnd,
                 class_name,
                 process_name,
                 title,
+                is_browser,
             })
         }
 
         MessageFromBrowser::UngroupTaskbarButton { hwnd, new_id } => {
             // Order here is important, otherwise icon gets stuck in Google Chrome
-            let hwnd_ = HWND(hwnd as isize);
-            clear_pinned_taskbar_icon(hwnd_);
-            ungroup_taskbar_button(hwnd_, &new_id);
-            prevent_pinning_taskbar_button(hwnd_);
-            allow_maximize_and_snapping(hwnd_);
+            wm.clear_pinned_taskbar_icon(hwnd);
+            wm.ungroup_taskbar_button(hwnd, &new_id);
+            wm.prevent_pinning_taskbar_button(hwnd);
+            wm.allow_maximize_and_snapping(hwnd);
             log(&format!("Ungroupped a window {}", hwnd));
             Ok(MessageToBrowser::Ok)
         }
 
-        MessageFromBrowser::SetTaskbarIcon { hwnd, icon_url } => {
-            let hwnd = HWND(hwnd as isize);
+        MessageFromBrowser::SetTaskbarIcon {
+            hwnd,
+            icon_url,
+            favicon_url,
+            icon_data,
+            mime_type,
+        } => {
+            if is_superseded() {
+                return Ok(MessageToBrowser::Superseded);
+            }
 
-            let url = url::Url::parse(&icon_url).map_err(|_| MessageToError::UrlParsingError {
-                message: "Invalid favicon URL".into(),
-            })?;
+            let favicon = if let Some(icon_data) = icon_data {
+                let bytes = base64::engine::general_purpose::STANDARD
+                    .decode(icon_data.trim())
+                    .map_err(|_| MessageToError::Error {
+                        message: "Invalid icon data".into(),
+                    })?;
+                favicons.get_favicon_from_bytes(&bytes, mime_type.as_deref())
+            } else if icon_url.starts_with("data:") {
+                let (mime_type, bytes) =
+                    decode_data_url(&icon_url).map_err(|_| MessageToError::UrlParsingError {
+                        message: "Invalid favicon data URL".into(),
+                    })?;
+                favicons.get_favicon_from_bytes(&bytes, Some(&mime_type))
+            } else {
+                let url =
+                    url::Url::parse(&icon_url).map_err(|_| MessageToError::UrlParsingError {
+                        message: "Invalid favicon URL".into(),
+                    })?;
+                favicons.get_favicon_from_url(&url, favicon_url.as_deref())
+            };
 
-            let favicon_path = get_favicon_from_url(&url).map_err(|err| MessageToError::Error {
+            // Fetching may take a while, don't override a newer icon or
+            // report errors the browser no longer cares about
+            if is_superseded() {
+                return Ok(MessageToBrowser::Superseded);
+            }
+
+            let favicon_path = favicon.map_err(|_err| MessageToError::Error {
                 message: "Failed to get favicon".into(),
                 // message: format!("{:?}", err),
             })?;
 
-            set_icon(hwnd, &favicon_path);
+            wm.set_icon(hwnd, &favicon_path);
             // set_pinned_taskbar_icon(hwnd, &favicon_path);
             // clear_pinned_taskbar_icon(hwnd);
 
             // When using popups in Firefox, the window is not maximizable, enable that
 
-            log(&format!("Icon set for hwnd {}", hwnd.0));
+            log(&format!("Icon set for hwnd {}", hwnd));
 
             Ok(MessageToBrowser::Ok)
         }
@@ -124,35 +235,881 @@ fn event_handler(msg: MessageFromBrowser) -> Result<MessageToBrowser, MessageToE
     }
 }
 
-pub fn main_event_loop() -> Result<(), MessageToError> {
+/// Number of threads handling requests concurrently
+const WORKER_COUNT: usize = 4;
+
+thread_local! {
+    /// ID of the request being handled, for the panic hook
+    static CURRENT_REQUEST_ID: RefCell<Option<serde_json::Value>> = const { RefCell::new(None) };
+}
+
+/// Tracks the latest icon request of each window
+///
+/// Icon requests are numbered as they are read, older requests of the same
+/// window are superseded by newer ones.
+#[derive(Default)]
+struct IconRequests {
+    latest: Mutex<(u64, HashMap<u32, u64>)>,
+}
+
+impl IconRequests {
+    fn register(&self, hwnd: u32) -> u64 {
+        let mut latest = self.latest.lock().unwrap();
+        latest.0 += 1;
+        let seq = latest.0;
+        latest.1.insert(hwnd, seq);
+        seq
+    }
+
+    fn is_superseded(&self, hwnd: u32, seq: u64) -> bool {
+        self.latest.lock().unwrap().1.get(&hwnd) != Some(&seq)
+    }
+}
+
+/// Queues of the requests of each window, so that they run in the order
+/// they were read
+///
+/// The extension relies on the order, e.g. ungrouping before setting the
+/// icon. The first request of an idle window goes to the workers, later ones
+/// wait here without holding a worker, and the worker that finishes a request
+/// goes on with the next one of the same window. Requests of different
+/// windows run in parallel.
+#[derive(Default)]
+struct WindowQueues {
+    /// Waiting requests of each window that has one in progress
+    queues: Mutex<HashMap<u32, VecDeque<Job>>>,
+}
+
+impl WindowQueues {
+    /// The job if the window is idle, otherwise it is queued
+    fn start(&self, hwnd: u32, job: Job) -> Option<Job> {
+        match self.queues.lock().unwrap().entry(hwnd) {
+            Entry::Occupied(mut queue) => {
+                queue.get_mut().push_back(job);
+                None
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(VecDeque::new());
+                Some(job)
+            }
+        }
+    }
+
+    /// Next job of the window after one finished, the window is idle again
+    /// if there is none
+    fn finish(&self, hwnd: u32) -> Option<Job> {
+        let mut queues = self.queues.lock().unwrap();
+        let next = queues.get_mut(&hwnd).and_then(VecDeque::pop_front);
+        if next.is_none() {
+            queues.remove(&hwnd);
+        }
+        next
+    }
+}
+
+/// Request with its place in the icon order
+struct Job {
+    envelope: Envelope<MessageFromBrowser>,
+    /// Number of the icon request, see `IconRequests`
+    seq: u64,
+}
+
+/// Handle messages from the browser until `Quit`, the end of input or a read
+/// error
+///
+/// Messages longer than the read limit of `codec` stop the loop. The browser
+/// closing the port is a clean shutdown like `Quit`, windows are restored
+/// either way.
+pub fn main_event_loop(
+    wm: &(impl WindowManager + Sync),
+    favicons: &FaviconProviders,
+    browser: &BrowserConfig,
+    codec: &NativeMessagingCodec,
+) -> Result<(), MessageToError> {
     // Send panic messages to the browser
-    panic::set_hook(Box::new(|info: &std::panic::PanicInfo| {
+    panic::set_hook(Box::new(|info: &std::panic::PanicHookInfo| {
         let response = MessageToError::Panic {
             message: format!("{}", info),
             file: info.location().map(|l| l.file().to_string()),
             line: info.location().map(|l| l.line()),
         };
-        log(&format!("Panic: {:?}", response));
-        let _ = send_message(std::io::stdout(), &response);
+        log_at(Level::Error, &format!("Panic: {:?}", response));
+        let id = CURRENT_REQUEST_ID.with(|id| id.borrow().clone());
+        let _ = send_message(std::io::stdout().lock(), &Envelope::new(id, response));
     }));
 
-    // Event loop
+    run_event_loop(
+        std::io::stdin(),
+        std::io::stdout(),
+        wm,
+        favicons,
+        browser,
+        codec,
+    )
+}
+
+/// Read requests and send them to the workers until `Quit`, the end of input
+/// or a read error
+///
+/// Invalid JSON does not end reading, its error is sent as a reply.
+fn read_requests(
+    mut input: impl Read,
+    job_tx: mpsc::Sender<Job>,
+    reply_tx: mpsc::Sender<serde_json::Value>,
+    icon_requests: &IconRequests,
+    window_queues: &WindowQueues,
+    output_error: &Mutex<Option<MessageToError>>,
+    codec: &NativeMessagingCodec,
+) -> Result<(), MessageToError> {
     loop {
-        // Read message error ends the loop
-        let msg = read_message(std::io::stdin())?;
+        // Replies can't be sent anymore, no point in handling more requests
+        if let Some(err) = output_error.lock().unwrap().take() {
+            return Err(err);
+        }
+        let envelope = match read_message(&mut input, codec) {
+            Ok(envelope) => envelope,
+            Err(err @ MessageError::Json { .. }) => {
+                let reply = Envelope::new(err.id().cloned(), MessageToError::from(&err));
+                if let Ok(reply) = serde_json::to_value(reply) {
+                    let _ = reply_tx.send(reply);
+                }
+                continue;
+            }
+            // Rest of the stream is unusable, tell the browser why we stop
+            Err(MessageError::Closed) => {
+                log("Input closed by the browser");
+                return Ok(());
+            }
+            Err(err @ MessageError::TooLarge { .. }) => {
+                log_at(Level::Warn, &format!("Message too large: {:?}", err));
+                if let Ok(reply) =
+                    serde_json::to_value(Envelope::new(None, MessageToError::from(&err)))
+                {
+                    let _ = reply_tx.send(reply);
+                }
+                return Err(MessageToError::from(&err));
+            }
+            Err(err) => return Err(MessageToError::from(&err)),
+        };
+
+        let seq = match envelope.message {
+            MessageFromBrowser::Quit => return Ok(()),
+            MessageFromBrowser::SetTaskbarIcon { hwnd, .. } => icon_requests.register(hwnd),
+            _ => 0,
+        };
+        let job = Job { envelope, seq };
+        let job = match job.envelope.message.hwnd() {
+            Some(hwnd) => window_queues.start(hwnd, job),
+            None => Some(job),
+        };
+        if let Some(job) = job {
+            let _ = job_tx.send(job);
+        }
+    }
+}
+
+/// Handle the request and send the reply
+fn handle_job(
+    job: Job,
+    wm: &impl WindowManager,
+    favicons: &FaviconProviders,
+    browser: &BrowserConfig,
+    icon_requests: &IconRequests,
+    reply_tx: &mpsc::Sender<serde_json::Value>,
+) {
+    let Job {
+        envelope: Envelope { id, message: msg },
+        seq,
+    } = job;
+    CURRENT_REQUEST_ID.with(|current| *current.borrow_mut() = id.clone());
+
+    let hwnd = match msg {
+        MessageFromBrowser::SetTaskbarIcon { hwnd, .. } => Some(hwnd),
+        _ => None,
+    };
+    let is_superseded = || hwnd.is_some_and(|hwnd| icon_requests.is_superseded(hwnd, seq));
+
+    // Panic hook has already sent the error to the browser
+    let Ok(result) = panic::catch_unwind(AssertUnwindSafe(|| {
+        event_handler(wm, favicons, browser, msg, &is_superseded)
+    })) else {
+        return;
+    };
+    let reply = match result {
+        Ok(msg) => serde_json::to_value(Envelope::new(id, msg)),
+        Err(msg) => serde_json::to_value(Envelope::new(id, msg)),
+    };
+    if let Ok(reply) = reply {
+        let _ = reply_tx.send(reply);
+    }
+}
+
+/// Write replies until all senders are dropped or writing fails
+///
+/// A reply over the size limit is replaced by a `messageTooLarge` error with
+/// the same `id`, so the browser isn't left waiting for it.
+fn write_replies(
+    mut output: impl Write,
+    replies: mpsc::Receiver<serde_json::Value>,
+    codec: &NativeMessagingCodec,
+) -> Result<(), MessageToError> {
+    for reply in replies {
+        let result = match codec.write_frame(&mut output, reply.to_string().as_bytes()) {
+            Err(FrameError::TooLarge { length, max_length }) => {
+                log_at(Level::Warn, &format!("Reply too large: {} bytes", length));
+                let error = Envelope::new(
+                    reply.get("id").cloned(),
+                    MessageToError::MessageTooLarge { length, max_length },
+                );
+                match serde_json::to_vec(&error) {
+                    Ok(payload) => codec.write_frame(&mut output, &payload),
+                    Err(_) => continue,
+                }
+            }
+            result => result,
+        };
+        match result {
+            Ok(()) => {}
+            Err(FrameError::TooLarge { .. }) => {
+                log_at(Level::Warn, "Dropped reply, its error is too large too");
+            }
+            Err(err) => return Err(MessageToError::from(&MessageError::from(err))),
+        }
+    }
+    Ok(())
+}
+
+/// Read requests from `input` and handle them concurrently
+///
+/// Replies are written to `output` from a single thread, in the order the
+/// requests complete. When writing fails, reading stops before the next
+/// request.
+fn run_event_loop(
+    mut input: impl Read,
+    output: impl Write + Send,
+    wm: &(impl WindowManager + Sync),
+    favicons: &FaviconProviders,
+    browser: &BrowserConfig,
+    codec: &NativeMessagingCodec,
+) -> Result<(), MessageToError> {
+    let (reply_tx, reply_rx) = mpsc::channel::<serde_json::Value>();
+    let (job_tx, job_rx) = mpsc::channel::<Job>();
+    let job_rx = Mutex::new(job_rx);
+    let icon_requests = IconRequests::default();
+    let window_queues = WindowQueues::default();
+    let output_error = Mutex::new(None);
 
-        // Event handler error does not end the loop, except Quit
-        match event_handler(msg) {
-            Ok(msg) => {
-                send_message(std::io::stdout(), &msg).unwrap();
+    let result = thread::scope(|scope| {
+        // Writer
+        let output_error = &output_error;
+        scope.spawn(move || {
+            if let Err(err) = write_replies(output, reply_rx, codec) {
+                log_at(Level::Warn, &format!("Failed to send reply: {:?}", err));
+                *output_error.lock().unwrap() = Some(err);
             }
-            Err(msg) => {
-                if let MessageToError::Quit = msg {
+        });
+
+        // Workers
+        for _ in 0..WORKER_COUNT {
+            let reply_tx = reply_tx.clone();
+            let (job_rx, icon_requests, window_queues) = (&job_rx, &icon_requests, &window_queues);
+            scope.spawn(move || loop {
+                let Ok(job) = job_rx.lock().unwrap().recv() else {
                     break;
+                };
+                let hwnd = job.envelope.message.hwnd();
+                let mut next = Some(job);
+                while let Some(job) = next {
+                    handle_job(job, wm, favicons, browser, icon_requests, &reply_tx);
+                    next = hwnd.and_then(|hwnd| window_queues.finish(hwnd));
                 }
-                send_message(std::io::stdout(), &msg).unwrap();
+            });
+        }
+
+        // Senders are dropped when reading ends, which stops the workers and
+        // then the writer. Leaving the scope waits for the requests in
+        // progress to finish.
+        read_requests(
+            &mut input,
+            job_tx,
+            reply_tx,
+            &icon_requests,
+            &window_queues,
+            output_error,
+            codec,
+        )
+    });
+    // Writing may fail after the last request was read
+    let result = result.and_then(|()| output_error.into_inner().unwrap().map_or(Ok(()), Err));
+
+    log(&format!("Shutting down: {:?}", result));
+    wm.restore_windows();
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::utils::favicon::GetFaviconError;
+    use crate::utils::favicon_cache::{FaviconCache, DEFAULT_MAX_SIZE};
+    use crate::utils::favicon_provider::{FaviconProvider, FaviconRequest, FetchedFavicon};
+    use crate::utils::test_support::png;
+    use crate::utils::window_manager::fake::{Call, FakeWindowManager};
+
+    /// Provider which takes a while and then fails
+    struct SlowProvider;
+
+    impl FaviconProvider for SlowProvider {
+        fn name(&self) -> &'static str {
+            "slow"
+        }
+
+        fn fetch(
+            &self,
+            _client: &reqwest::blocking::Client,
+            _request: &FaviconRequest,
+        ) -> Result<FetchedFavicon, GetFaviconError> {
+            thread::sleep(Duration::from_millis(300));
+            Err(GetFaviconError::NotFound)
+        }
+    }
+
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Input arriving in stages, like requests sent by the browser over time
+    struct StagedInput {
+        stages: VecDeque<Vec<u8>>,
+        delay: Duration,
+        current: std::io::Cursor<Vec<u8>>,
+    }
+
+    impl Read for StagedInput {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            loop {
+                let n = self.current.read(buf)?;
+                if n > 0 || buf.is_empty() {
+                    return Ok(n);
+                }
+                let Some(stage) = self.stages.pop_front() else {
+                    return Ok(0);
+                };
+                thread::sleep(self.delay);
+                self.current = std::io::Cursor::new(stage);
             }
         }
     }
-    Ok(())
+
+    fn frames(messages: &[serde_json::Value]) -> Vec<u8> {
+        let codec = NativeMessagingCodec::default();
+        messages
+            .iter()
+            .flat_map(|message| codec.encode(&serde_json::to_vec(message).unwrap()).unwrap())
+            .collect()
+    }
+
+    fn parse_frames(mut bytes: &[u8]) -> Vec<serde_json::Value> {
+        let codec = NativeMessagingCodec::default();
+        let mut messages = Vec::new();
+        while !bytes.is_empty() {
+            let frame = codec.read_frame(&mut bytes).unwrap();
+            messages.push(serde_json::from_slice(&frame).unwrap());
+        }
+        messages
+    }
+
+    fn run(messages: &[serde_json::Value]) -> Vec<serde_json::Value> {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = FaviconCache::new(dir.path().into(), Duration::ZERO, DEFAULT_MAX_SIZE);
+        let favicons = FaviconProviders::new(vec![Box::new(SlowProvider)], cache);
+        let wm = FakeWindowManager {
+            active_window: 42,
+            ..Default::default()
+        };
+        let output = SharedBuffer::default();
+        run_event_loop(
+            &frames(messages)[..],
+            output.clone(),
+            &wm,
+            &favicons,
+            &BrowserConfig::default(),
+            &NativeMessagingCodec::default(),
+        )
+        .unwrap();
+        let bytes = output.0.lock().unwrap().clone();
+        parse_frames(&bytes)
+    }
+
+    #[test]
+    fn test_slow_icon_does_not_block_other_requests() {
+        let replies = run(&[
+            serde_json::json!({"id": 1, "type": "setTaskbarIcon", "hwnd": 7, "iconUrl": "https://slow.test/"}),
+            serde_json::json!({"id": 2, "type": "getActiveWindow"}),
+            serde_json::json!({"type": "quit"}),
+        ]);
+        assert_eq!(replies.len(), 2);
+        assert_eq!(replies[0]["id"], 2);
+        assert_eq!(replies[0]["type"], "activeWindow");
+        assert_eq!(replies[1]["id"], 1);
+        assert_eq!(replies[1]["type"], "error");
+    }
+
+    #[test]
+    fn test_icon_requests_are_coalesced() {
+        let replies = run(&[
+            serde_json::json!({"id": 1, "type": "setTaskbarIcon", "hwnd": 7, "iconUrl": "https://slow.test/"}),
+            serde_json::json!({"id": 2, "type": "setTaskbarIcon", "hwnd": 7, "iconUrl": "https://slow.test/"}),
+            serde_json::json!({"type": "quit"}),
+        ]);
+        assert_eq!(replies.len(), 2);
+        let superseded = replies.iter().find(|reply| reply["id"] == 1).unwrap();
+        assert_eq!(superseded["type"], "superseded");
+        let latest = replies.iter().find(|reply| reply["id"] == 2).unwrap();
+        assert_eq!(latest["type"], "error");
+    }
+
+    #[test]
+    fn test_message_too_large_stops_loop() {
+        let wm = FakeWindowManager::default();
+        let output = SharedBuffer::default();
+        let input = frames(&[serde_json::json!({"type": "getActiveWindow"})]);
+        let result = run_event_loop(
+            &input[..],
+            output.clone(),
+            &wm,
+            &FaviconProviders::default(),
+            &BrowserConfig::default(),
+            &NativeMessagingCodec::new(8),
+        );
+        assert!(matches!(
+            result,
+            Err(MessageToError::MessageTooLarge { max_length: 8, .. })
+        ));
+
+        let replies = parse_frames(&output.0.lock().unwrap());
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0]["type"], "messageTooLarge");
+        assert_eq!(wm.calls(), vec![Call::RestoreWindows]);
+    }
+
+    #[test]
+    fn test_reply_too_large_is_replaced() {
+        let wm = FakeWindowManager {
+            title: "x".repeat(200),
+            ..Default::default()
+        };
+        let output = SharedBuffer::default();
+        let input = frames(&[
+            serde_json::json!({"id": 1, "type": "getActiveWindow"}),
+            serde_json::json!({"id": 2, "type": "ungroupTaskbarButton", "hwnd": 7, "newId": "a"}),
+        ]);
+        let codec = NativeMessagingCodec {
+            max_write_length: 100,
+            ..Default::default()
+        };
+        let result = run_event_loop(
+            &input[..],
+            output.clone(),
+            &wm,
+            &FaviconProviders::default(),
+            &BrowserConfig::default(),
+            &codec,
+        );
+        assert!(result.is_ok());
+
+        let replies = parse_frames(&output.0.lock().unwrap());
+        assert_eq!(replies.len(), 2);
+        let too_large = replies.iter().find(|reply| reply["id"] == 1).unwrap();
+        assert_eq!(too_large["type"], "messageTooLarge");
+        assert_eq!(too_large["maxLength"], 100);
+        assert!(replies.iter().any(|reply| reply["id"] == 2));
+    }
+
+    #[test]
+    fn test_write_error_stops_loop() {
+        struct ClosedOutput;
+
+        impl Write for ClosedOutput {
+            fn write(&mut self, _buf: &[u8]) -> std::io::Result<usize> {
+                Err(std::io::ErrorKind::BrokenPipe.into())
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let wm = FakeWindowManager::default();
+        let input = frames(&[serde_json::json!({"id": 1, "type": "getActiveWindow"})]);
+        let result = run_event_loop(
+            &input[..],
+            ClosedOutput,
+            &wm,
+            &FaviconProviders::default(),
+            &BrowserConfig::default(),
+            &NativeMessagingCodec::default(),
+        );
+        match result {
+            Err(MessageToError::IoError { kind, .. }) => {
+                assert_eq!(kind, std::io::ErrorKind::BrokenPipe.to_string())
+            }
+            result => panic!("unexpected result {:?}", result),
+        }
+        assert_eq!(wm.calls().last(), Some(&Call::RestoreWindows));
+    }
+
+    #[test]
+    fn test_end_of_input_is_clean_shutdown() {
+        let wm = FakeWindowManager::default();
+        let output = SharedBuffer::default();
+        let input = frames(&[serde_json::json!({"id": 1, "type": "getActiveWindow"})]);
+        let result = run_event_loop(
+            &input[..],
+            output.clone(),
+            &wm,
+            &FaviconProviders::default(),
+            &BrowserConfig::default(),
+            &NativeMessagingCodec::default(),
+        );
+        assert!(result.is_ok());
+        assert_eq!(parse_frames(&output.0.lock().unwrap()).len(), 1);
+        assert_eq!(wm.calls().last(), Some(&Call::RestoreWindows));
+
+        // Input ending inside a message is an error
+        let result = run_event_loop(
+            &input[..input.len() - 1],
+            SharedBuffer::default(),
+            &wm,
+            &FaviconProviders::default(),
+            &BrowserConfig::default(),
+            &NativeMessagingCodec::default(),
+        );
+        assert!(matches!(result, Err(MessageToError::IoError { .. })));
+    }
+
+    #[test]
+    fn test_requests_of_a_window_run_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = FaviconCache::new(dir.path().into(), Duration::ZERO, DEFAULT_MAX_SIZE);
+        let favicons = FaviconProviders::new(vec![], cache);
+        let wm = FakeWindowManager {
+            delay: Duration::from_millis(200),
+            ..Default::default()
+        };
+        let icon = base64::engine::general_purpose::STANDARD.encode(png(16, 16));
+        let output = SharedBuffer::default();
+        run_event_loop(
+            &frames(&[
+                serde_json::json!({"id": 1, "type": "ungroupTaskbarButton", "hwnd": 7, "newId": "a"}),
+                serde_json::json!({"id": 2, "type": "setTaskbarIcon", "hwnd": 7, "iconData": icon}),
+                serde_json::json!({"id": 3, "type": "setTaskbarIcon", "hwnd": 8, "iconData": icon}),
+                serde_json::json!({"type": "quit"}),
+            ])[..],
+            output.clone(),
+            &wm,
+            &favicons,
+            &BrowserConfig::default(),
+            &NativeMessagingCodec::default(),
+        )
+        .unwrap();
+        assert_eq!(parse_frames(&output.0.lock().unwrap()).len(), 3);
+
+        let calls = wm.calls();
+        let position = |matches: &dyn Fn(&Call) -> bool| calls.iter().position(matches).unwrap();
+        let ungrouped = position(&|call| *call == Call::AllowMaximizeAndSnapping(7));
+        let icon_set = position(&|call| matches!(call, Call::SetIcon(7, _)));
+        let other_icon_set = position(&|call| matches!(call, Call::SetIcon(8, _)));
+        assert!(ungrouped < icon_set, "{:?}", calls);
+        // Other windows don't wait for the slow one
+        assert!(other_icon_set < ungrouped, "{:?}", calls);
+    }
+
+    #[test]
+    fn test_queued_requests_of_a_window_do_not_block_others() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = FaviconCache::new(dir.path().into(), Duration::ZERO, DEFAULT_MAX_SIZE);
+        let favicons = FaviconProviders::new(vec![Box::new(SlowProvider)], cache);
+        let wm = FakeWindowManager::default();
+        let icon = |id| serde_json::json!({"id": id, "type": "setTaskbarIcon", "hwnd": 7, "iconUrl": "https://slow.test/"});
+        // Slow request is in progress when the others arrive
+        let input = StagedInput {
+            stages: VecDeque::from([
+                frames(&[icon(1)]),
+                frames(&[
+                    icon(2),
+                    icon(3),
+                    icon(4),
+                    serde_json::json!({"id": 5, "type": "getActiveWindow"}),
+                ]),
+            ]),
+            delay: Duration::from_millis(50),
+            current: Default::default(),
+        };
+        let output = SharedBuffer::default();
+        run_event_loop(
+            input,
+            output.clone(),
+            &wm,
+            &favicons,
+            &BrowserConfig::default(),
+            &NativeMessagingCodec::default(),
+        )
+        .unwrap();
+
+        let replies = parse_frames(&output.0.lock().unwrap());
+        assert_eq!(replies.len(), 5);
+        assert_eq!(replies[0]["id"], 5);
+        let window_replies = replies[1..]
+            .iter()
+            .map(|reply| reply["id"].as_u64().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(window_replies, vec![1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_icon_requests_tracking() {
+        let icon_requests = IconRequests::default();
+        let first = icon_requests.register(7);
+        let other_window = icon_requests.register(8);
+        assert!(!icon_requests.is_superseded(7, first));
+
+        let second = icon_requests.register(7);
+        assert!(icon_requests.is_superseded(7, first));
+        assert!(!icon_requests.is_superseded(7, second));
+        assert!(!icon_requests.is_superseded(8, other_window));
+    }
+
+    #[test]
+    fn test_superseded_icon_is_not_set() {
+        let wm = FakeWindowManager::default();
+        let msg = MessageFromBrowser::SetTaskbarIcon {
+            hwnd: 7,
+            icon_url: "https://example.com/".into(),
+            favicon_url: None,
+            icon_data: None,
+            mime_type: None,
+        };
+        let response = event_handler(
+            &wm,
+            &FaviconProviders::default(),
+            &BrowserConfig::default(),
+            msg,
+            &|| true,
+        );
+        assert!(matches!(response, Ok(MessageToBrowser::Superseded)));
+        assert!(wm.calls().is_empty());
+    }
+
+    #[test]
+    fn test_get_active_window() {
+        let wm = FakeWindowManager {
+            active_window: 42,
+            title: "Example - Mozilla Firefox".into(),
+            class_name: "MozillaWindowClass".into(),
+            process_name: "firefox.exe".into(),
+            ..Default::default()
+        };
+
+        let response = event_handler(
+            &wm,
+            &FaviconProviders::default(),
+            &BrowserConfig::default(),
+            MessageFromBrowser::GetActiveWindow,
+            &|| false,
+        )
+        .unwrap();
+        match response {
+            MessageToBrowser::ActiveWindow {
+                hwnd,
+                class_name,
+                title,
+                process_name,
+                is_browser,
+            } => {
+                assert_eq!(hwnd, 42);
+                assert_eq!(class_name, "MozillaWindowClass");
+                assert_eq!(title, "Example - Mozilla Firefox");
+                assert_eq!(process_name, "firefox.exe");
+                assert!(is_browser);
+            }
+            other => panic!("Unexpected response {:?}", other),
+        }
+        assert_eq!(wm.calls()[0], Call::GetActiveWindow);
+    }
+
+    #[test]
+    fn test_ungroup_taskbar_button_order() {
+        let wm = FakeWindowManager::default();
+        let msg = MessageFromBrowser::UngroupTaskbarButton {
+            hwnd: 7,
+            new_id: "123".into(),
+        };
+
+        let response = event_handler(
+            &wm,
+            &FaviconProviders::default(),
+            &BrowserConfig::default(),
+            msg,
+            &|| false,
+        )
+        .unwrap();
+        assert!(matches!(response, MessageToBrowser::Ok));
+        assert_eq!(
+            wm.calls(),
+            vec![
+                Call::ClearPinnedTaskbarIcon(7),
+                Call::UngroupTaskbarButton(7, "123".into()),
+                Call::PreventPinningTaskbarButton(7),
+                Call::AllowMaximizeAndSnapping(7),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_set_taskbar_icon_invalid_url() {
+        let wm = FakeWindowManager::default();
+        let msg = MessageFromBrowser::SetTaskbarIcon {
+            hwnd: 7,
+            icon_url: "not a url".into(),
+            favicon_url: None,
+            icon_data: None,
+            mime_type: None,
+        };
+
+        let response = event_handler(
+            &wm,
+            &FaviconProviders::default(),
+            &BrowserConfig::default(),
+            msg,
+            &|| false,
+        );
+        assert!(matches!(
+            response,
+            Err(MessageToError::UrlParsingError { .. })
+        ));
+        assert!(wm.calls().is_empty());
+    }
+
+    #[test]
+    fn test_set_taskbar_icon_invalid_data() {
+        let wm = FakeWindowManager::default();
+        let msg = MessageFromBrowser::SetTaskbarIcon {
+            hwnd: 7,
+            icon_url: "data:image/png;base64,not base64".into(),
+            favicon_url: None,
+            icon_data: None,
+            mime_type: None,
+        };
+        let response = event_handler(
+            &wm,
+            &FaviconProviders::default(),
+            &BrowserConfig::default(),
+            msg,
+            &|| false,
+        );
+        assert!(matches!(
+            response,
+            Err(MessageToError::UrlParsingError { .. })
+        ));
+
+        let msg = MessageFromBrowser::SetTaskbarIcon {
+            hwnd: 7,
+            icon_url: "".into(),
+            favicon_url: None,
+            icon_data: Some("AQID".into()),
+            mime_type: Some("image/png".into()),
+        };
+        let response = event_handler(
+            &wm,
+            &FaviconProviders::default(),
+            &BrowserConfig::default(),
+            msg,
+            &|| false,
+        );
+        assert!(matches!(response, Err(MessageToError::Error { .. })));
+        assert!(wm.calls().is_empty());
+    }
+
+    #[test]
+    fn test_deserialize_set_taskbar_icon_with_data() {
+        let msg: MessageFromBrowser = serde_json::from_str(
+            r#"{"type":"setTaskbarIcon","hwnd":7,"iconData":"AQID","mimeType":"image/png"}"#,
+        )
+        .unwrap();
+        match msg {
+            MessageFromBrowser::SetTaskbarIcon {
+                hwnd,
+                icon_url,
+                icon_data,
+                mime_type,
+                ..
+            } => {
+                assert_eq!(hwnd, 7);
+                assert_eq!(icon_url, "");
+                assert_eq!(icon_data.as_deref(), Some("AQID"));
+                assert_eq!(mime_type.as_deref(), Some("image/png"));
+            }
+            other => panic!("Unexpected message {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_envelope_id_is_optional() {
+        let envelope: Envelope<MessageFromBrowser> =
+            serde_json::from_str(r#"{"type":"getActiveWindow"}"#).unwrap();
+        assert!(envelope.id.is_none());
+        assert!(matches!(
+            envelope.message,
+            MessageFromBrowser::GetActiveWindow
+        ));
+        assert_eq!(
+            serde_json::to_string(&Envelope::new(None, MessageToBrowser::Ok)).unwrap(),
+            r#"{"type":"ok"}"#
+        );
+    }
+
+    #[test]
+    fn test_envelope_id_is_echoed_verbatim() {
+        let envelope: Envelope<MessageFromBrowser> = serde_json::from_str(
+            r#"{"id":{"tab":3,"seq":"a"},"type":"ungroupTaskbarButton","hwnd":7,"newId":"3"}"#,
+        )
+        .unwrap();
+        let response = Envelope::new(
+            envelope.id,
+            MessageToError::Error {
+                message: "Failed".into(),
+            },
+        );
+        assert_eq!(
+            serde_json::to_value(&response).unwrap(),
+            serde_json::json!({"id": {"tab": 3, "seq": "a"}, "type": "error", "message": "Failed"})
+        );
+    }
+
+    #[test]
+    fn test_quit() {
+        let wm = FakeWindowManager::default();
+        let response = event_handler(
+            &wm,
+            &FaviconProviders::default(),
+            &BrowserConfig::default(),
+            MessageFromBrowser::Quit,
+            &|| false,
+        );
+        assert!(matches!(response, Err(MessageToError::Quit)));
+        assert!(wm.calls().is_empty());
+    }
 }
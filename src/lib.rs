@@ -0,0 +1,22 @@
+//! Native messaging host for the FBrowserHelper extension
+//!
+//! The protocol types are in [`events`] and the framing in
+//! [`native_messaging`], which can be used for other native hosts too.
+//! Favicons are fetched and converted to ICO files with [`favicon`], and
+//! [`native_manifest_installer`] registers the host with the browsers, which
+//! [`native_manifest_doctor`] checks. Settings are read by [`config`].
+
+pub mod events;
+mod utils;
+
+pub use utils::{
+    config, favicon, favicon_cache, favicon_provider, log, native_manifest_doctor,
+    native_manifest_installer, native_messaging, window_manager,
+};
+
+#[cfg(windows)]
+pub use utils::win32;
+#[cfg(target_os = "linux")]
+pub use utils::x11;
+
+pub(crate) use utils::log::log;
@@ -1,10 +1,24 @@
-use clap::Parser;
+use std::io::Write;
+use std::process::ExitCode;
 
-mod utils;
-use events::main_event_loop;
-use utils::native_manifest_installer::{install, Browser, NativeManifestJson};
-mod events;
-pub(crate) use utils::log::log;
+use clap::{Parser, Subcommand, ValueEnum};
+
+use fbrowserhelper::config::Config;
+#[cfg(any(windows, target_os = "linux"))]
+use fbrowserhelper::events::main_event_loop;
+use fbrowserhelper::events::MessageToError;
+#[cfg(any(windows, target_os = "linux"))]
+use fbrowserhelper::favicon::FaviconProviders;
+use fbrowserhelper::log::{self, Level};
+use fbrowserhelper::native_manifest_doctor::{diagnose, format_reports, installation_ok};
+use fbrowserhelper::native_manifest_installer::{
+    browsers_sharing_location, find_moved_installs, install, parse_allowed_extension,
+    parse_allowed_origin, parse_host_name, relocate, shared_location, uninstall, Browser,
+    InstallScope, NativeManifestJson, RELATIVE_PATH_SUPPORTED,
+};
+#[cfg(any(windows, target_os = "linux"))]
+use fbrowserhelper::native_messaging::NativeMessagingCodec;
+use fbrowserhelper::native_messaging::{send_message, Envelope};
 
 // Clap intro
 //
@@ -36,11 +50,206 @@ struct Opts {
     /// Install to browsers, separate by comma
     #[arg(short, long, use_value_delimiter = true, value_name = "BROWSERS")]
     install: Vec<Browser>,
+
+    /// Uninstall from browsers, separate by comma
+    #[arg(
+        short,
+        long,
+        use_value_delimiter = true,
+        value_name = "BROWSERS",
+        conflicts_with = "install"
+    )]
+    uninstall: Vec<Browser>,
+
+    /// Point to the executable relative to the manifest, so the folder can be
+    /// moved (Windows only)
+    #[arg(long, requires = "install")]
+    portable: bool,
+
+    /// Install or uninstall for all users instead of the current user
+    #[arg(long)]
+    system: bool,
+
+    /// Name of the native messaging host
+    #[arg(long, global = true, default_value = "f_browser_helper_app", value_parser = parse_host_name)]
+    host_name: String,
+
+    /// Description of the native messaging host
+    #[arg(long, global = true, default_value = "Browser helper app")]
+    description: String,
+
+    /// Origins allowed to connect in Chromium-based browsers, separate by comma
+    #[arg(
+        long = "allowed-origin",
+        global = true,
+        use_value_delimiter = true,
+        value_name = "ORIGINS",
+        default_value = "chrome-extension://dnmkkgomoldfnbpjolhekmnoligmhdnc/",
+        value_parser = parse_allowed_origin
+    )]
+    allowed_origins: Vec<String>,
+
+    /// Extension IDs allowed to connect in Firefox-based browsers, separate by comma
+    #[arg(
+        long = "allowed-extension",
+        global = true,
+        use_value_delimiter = true,
+        value_name = "IDS",
+        default_value = "f_browser_helper_ext@oksidi.com",
+        value_parser = parse_allowed_extension
+    )]
+    allowed_extensions: Vec<String>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Check the installation for each browser, exits with 1 if an
+    /// installed host doesn't work or none is installed
+    Doctor {
+        /// Print the report as JSON
+        #[arg(long)]
+        json: bool,
+
+        /// Check only these browsers, each of them must have a working host
+        #[arg(long, use_value_delimiter = true, value_name = "BROWSERS")]
+        browser: Vec<Browser>,
+    },
+
+    /// Point the manifests of a moved installation to this executable
+    Relocate,
+
+    /// Inspect the config file
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum ConfigCommand {
+    /// Print the effective config, including the defaults
+    Show,
+}
+
+/// Exit code when reading from or writing to the browser failed
+#[cfg(any(windows, target_os = "linux"))]
+const EXIT_IO_ERROR: u8 = 3;
+
+/// Exit code when the browser sent a message that stopped the event loop,
+/// e.g. one over the size limit
+#[cfg(any(windows, target_os = "linux"))]
+const EXIT_PROTOCOL_ERROR: u8 = 4;
+
+/// Exit code for the result of the event loop
+///
+/// The browser closing the port is a clean shutdown, like `Quit`. Usage
+/// errors exit with 2 and other errors with 1.
+#[cfg(any(windows, target_os = "linux"))]
+fn event_loop_exit_code(result: &Result<(), MessageToError>) -> ExitCode {
+    match result {
+        Ok(()) | Err(MessageToError::Quit) => ExitCode::SUCCESS,
+        Err(MessageToError::IoError { .. }) => ExitCode::from(EXIT_IO_ERROR),
+        Err(MessageToError::MessageTooLarge { .. } | MessageToError::JsonParseError { .. }) => {
+            ExitCode::from(EXIT_PROTOCOL_ERROR)
+        }
+        Err(_) => ExitCode::FAILURE,
+    }
+}
+
+/// Print the output of a subcommand
+///
+/// The output may be piped to a reader that stops early, e.g.
+/// `doctor --json | head`, which is not an error.
+fn print_output(output: &str) -> Result<(), &'static str> {
+    let mut stdout = std::io::stdout().lock();
+    match stdout
+        .write_all(output.as_bytes())
+        .and_then(|()| stdout.flush())
+    {
+        Ok(()) => Ok(()),
+        Err(err) if err.kind() == std::io::ErrorKind::BrokenPipe => Ok(()),
+        Err(_) => Err("Failed to write to stdout"),
+    }
+}
+
+pub fn main() -> ExitCode {
+    let code = match run() {
+        Ok(code) => code,
+        Err(err) => {
+            log::log_at(Level::Error, err);
+            eprintln!("Error: {}", err);
+            ExitCode::FAILURE
+        }
+    };
+    log::flush();
+    code
 }
 
-pub fn main() -> Result<(), &'static str> {
+/// Handle the messages of the extension until it disconnects
+#[cfg(any(windows, target_os = "linux"))]
+fn serve_extension(config: &Config) -> Result<ExitCode, &'static str> {
+    let mut favicons = FaviconProviders::default().with_cache(config.favicon.cache());
+    if let Some(url_template) = &config.favicon.service_url {
+        favicons = favicons.with_service(url_template);
+    }
+    let codec = NativeMessagingCodec::new(config.messaging.max_message_size);
+
+    #[cfg(windows)]
+    let result = main_event_loop(
+        &fbrowserhelper::win32::Win32WindowManager::default()
+            .with_icon_sizes(&config.window.icon_sizes),
+        &favicons,
+        &config.browser,
+        &codec,
+    );
+
+    #[cfg(target_os = "linux")]
+    let result = main_event_loop(
+        &fbrowserhelper::x11::X11WindowManager::connect()?
+            .with_icon_sizes(&config.window.icon_sizes),
+        &favicons,
+        &config.browser,
+        &codec,
+    );
+
+    Ok(event_loop_exit_code(&result))
+}
+
+// No window manager backend exists for other platforms yet
+#[cfg(not(any(windows, target_os = "linux")))]
+fn serve_extension(_config: &Config) -> Result<ExitCode, &'static str> {
+    Err("Window management is not supported on this platform")
+}
+
+fn run() -> Result<ExitCode, &'static str> {
     let args = Opts::parse();
-    log(&format!("Starting... {:?}", args));
+
+    // Defaults are used if the config can't be, the error is reported once
+    // logging and messaging are up
+    let config_path = Config::default_path();
+    let config = match config_path.as_deref() {
+        Some(path) => Config::load(path),
+        None => Config::default()
+            .with_env_overrides()
+            .and_then(Config::validated),
+    };
+    let (config, config_error) = match config {
+        Ok(config) => (config, None),
+        Err(err) => (Config::default(), Some(err)),
+    };
+
+    log::init(if args.extension.is_some() {
+        config.log.logger()
+    } else {
+        config.log.cli_logger()
+    });
+    log::log(&format!("Starting... {:?}", args));
+    if let Some(err) = &config_error {
+        log::log_at(Level::Warn, &format!("{}", err));
+    }
 
     // Get current executable path
     let current_exe_path =
@@ -48,28 +257,139 @@ pub fn main() -> Result<(), &'static str> {
 
     // If extension is provided, run event loop
     if args.extension.is_some() {
-        main_event_loop();
+        // Browser shows the host's stderr only in its own logs, tell the
+        // extension that the config was ignored
+        if let Some(err) = &config_error {
+            let error = MessageToError::ConfigError {
+                message: format!("{}", err),
+            };
+            let _ = send_message(std::io::stdout().lock(), &Envelope::new(None, error));
+        }
+
+        return serve_extension(&config);
+    }
+
+    let native_manifest_json = NativeManifestJson {
+        path: current_exe_path,
+        name: args.host_name,
+        description: args.description,
+        type_: "stdio".into(),
+        allowed_origins: args.allowed_origins,
+        allowed_extensions: args.allowed_extensions,
+    };
+
+    let scope = if args.system {
+        InstallScope::System
+    } else {
+        InstallScope::User
+    };
+
+    match args.command {
+        Some(Command::Doctor { json, browser }) => {
+            let browsers = if browser.is_empty() {
+                Browser::value_variants()
+            } else {
+                &browser
+            };
+            let reports = diagnose(browsers, &native_manifest_json);
+            let output = if json {
+                serde_json::to_string_pretty(&reports).map_err(|_| "Failed to serialize JSON")?
+                    + "\n"
+            } else {
+                format_reports(&reports)
+            };
+            print_output(&output)?;
+            return Ok(if installation_ok(&reports, !browser.is_empty()) {
+                ExitCode::SUCCESS
+            } else {
+                ExitCode::FAILURE
+            });
+        }
+        Some(Command::Relocate) => {
+            let moved = find_moved_installs(
+                Browser::value_variants(),
+                &native_manifest_json.name,
+                &native_manifest_json.path,
+            );
+            if moved.is_empty() {
+                println!("All manifests point to this executable");
+            }
+            for moved in moved {
+                println!(
+                    "Pointing {:?} from {} to {}",
+                    moved.browser,
+                    moved.exe_path.display(),
+                    native_manifest_json.path.display()
+                );
+                relocate(&moved, &native_manifest_json, &native_manifest_json.path)?;
+            }
+            return Ok(ExitCode::SUCCESS);
+        }
+        Some(Command::Config {
+            command: ConfigCommand::Show,
+        }) => {
+            let mut output = match &config_path {
+                Some(path) => format!("# {}\n", path.display()),
+                None => "# No config directory, using the defaults\n".to_string(),
+            };
+            if let Some(err) = &config_error {
+                output.push_str(&format!("# {}, using the defaults\n", err));
+            }
+            output.push_str(&config.to_toml());
+            print_output(&output)?;
+            return Ok(ExitCode::SUCCESS);
+        }
+        None => {}
     }
 
     // Do installation
     if !args.install.is_empty() {
-        let native_manifest_json = NativeManifestJson {
-            path: current_exe_path,
-            name: "f_browser_helper_app".into(),
-            description: "Browser helper app".into(),
-            type_: "stdio".into(),
-            allowed_origins: vec!["chrome-extension://dnmkkgomoldfnbpjolhekmnoligmhdnc/".into()],
-            allowed_extensions: vec!["f_browser_helper_ext@oksidi.com".into()],
-        };
+        if args.portable && !RELATIVE_PATH_SUPPORTED {
+            println!("Relative paths are not supported on this platform, using the full path");
+        }
 
         // Install for each browser
         for browser in args.install {
             println!("Installing for {:?}", browser);
-            install(browser, &native_manifest_json)?;
+            if let Some(owner) = shared_location(browser) {
+                println!(
+                    "  {:?} reads the hosts of {:?}, installing there",
+                    browser, owner
+                );
+            }
+            install(browser, &native_manifest_json, scope, args.portable)?;
+        }
+    }
+
+    // Do uninstallation
+    if !args.uninstall.is_empty() {
+        for browser in args.uninstall {
+            println!("Uninstalling from {:?}", browser);
+            if let Some(owner) = shared_location(browser) {
+                println!(
+                    "  {:?} reads the hosts of {:?}, uninstall from {:?} to remove it",
+                    browser, owner, owner
+                );
+                continue;
+            }
+            let sharing = browsers_sharing_location(browser);
+            if !sharing.is_empty() {
+                println!(
+                    "  Also removes it from {:?}, which read the same hosts",
+                    sharing
+                );
+            }
+            let removed = uninstall(browser, &native_manifest_json, scope)?;
+            if removed.is_empty() {
+                println!("  Nothing to remove");
+            }
+            for item in removed {
+                println!("  Removed {}", item);
+            }
         }
     }
 
-    log("Quitting...");
+    log::log("Quitting...");
 
-    Ok(())
+    Ok(ExitCode::SUCCESS)
 }
@@ -0,0 +1,498 @@
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::utils::favicon_cache::{FaviconCache, DEFAULT_MAX_SIZE, DEFAULT_TTL};
+use crate::utils::log::{self, Level, Logger, RotatingFile};
+use crate::utils::native_messaging::DEFAULT_MAX_MESSAGE_FROM_BROWSER;
+use crate::utils::window_manager::DEFAULT_ICON_SIZES;
+
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+const BYTES_PER_MB: u64 = 1024 * 1024;
+
+/// Windows executables and their Linux counterparts
+pub const DEFAULT_BROWSER_PROCESS_NAMES: [&str; 21] = [
+    "chrome.exe",
+    "msedge.exe",
+    "firefox.exe",
+    "brave.exe",
+    "vivaldi.exe",
+    "opera.exe",
+    "Arc.exe",
+    "librewolf.exe",
+    "waterfox.exe",
+    "/chrome",
+    "/chromium",
+    "/msedge",
+    "/firefox",
+    "/firefox-bin",
+    "/firefox-esr",
+    "/brave",
+    "/vivaldi-bin",
+    "/opera",
+    "/librewolf",
+    "/waterfox",
+    "/waterfox-bin",
+];
+
+/// Settings read from `config.toml`, missing values use the defaults
+///
+/// ```toml
+/// [favicon]
+/// service_url = "https://icons.example.com/{domain}.ico"
+/// cache_dir = "/tmp/favicons"
+///
+/// [window]
+/// icon_sizes = [32, 64]
+///
+/// [browser]
+/// process_names = ["firefox.exe", "/firefox"]
+///
+/// [log]
+/// level = "debug"
+/// stderr = true
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub favicon: FaviconConfig,
+    pub window: WindowConfig,
+    pub browser: BrowserConfig,
+    pub messaging: MessagingConfig,
+    pub log: LogConfig,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct FaviconConfig {
+    /// Third-party favicon service, see `ServiceFaviconProvider`
+    ///
+    /// Opt-in, the service sees every visited domain.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub service_url: Option<String>,
+    pub cache_dir: PathBuf,
+    pub cache_ttl_days: u64,
+    pub cache_max_size_mb: u64,
+}
+
+impl Default for FaviconConfig {
+    fn default() -> Self {
+        FaviconConfig {
+            service_url: None,
+            cache_dir: FaviconCache::default_dir(),
+            cache_ttl_days: DEFAULT_TTL.as_secs() / SECS_PER_DAY,
+            cache_max_size_mb: DEFAULT_MAX_SIZE / BYTES_PER_MB,
+        }
+    }
+}
+
+impl FaviconConfig {
+    pub fn cache(&self) -> FaviconCache {
+        FaviconCache::new(
+            self.cache_dir.clone(),
+            Duration::from_secs(self.cache_ttl_days.saturating_mul(SECS_PER_DAY)),
+            self.cache_max_size_mb.saturating_mul(BYTES_PER_MB),
+        )
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct WindowConfig {
+    /// Sizes of the window icons, Windows uses the smallest and the largest
+    pub icon_sizes: Vec<u32>,
+}
+
+impl Default for WindowConfig {
+    fn default() -> Self {
+        WindowConfig {
+            icon_sizes: DEFAULT_ICON_SIZES.to_vec(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct BrowserConfig {
+    /// Windows whose process name ends with one of these are browser windows
+    pub process_names: Vec<String>,
+}
+
+impl Default for BrowserConfig {
+    fn default() -> Self {
+        BrowserConfig {
+            process_names: DEFAULT_BROWSER_PROCESS_NAMES.map(String::from).to_vec(),
+        }
+    }
+}
+
+impl BrowserConfig {
+    pub fn is_browser_process(&self, process_name: &str) -> bool {
+        self.process_names
+            .iter()
+            .any(|name| process_name.ends_with(name.as_str()))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct MessagingConfig {
+    /// Longest message accepted from the browser, in bytes
+    pub max_message_size: u32,
+}
+
+impl Default for MessagingConfig {
+    fn default() -> Self {
+        MessagingConfig {
+            max_message_size: DEFAULT_MAX_MESSAGE_FROM_BROWSER,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    pub level: Level,
+    /// Write to `dir` while serving the browser, disable to log only to stderr
+    pub file: bool,
+    pub dir: PathBuf,
+    /// Size after which the log file is rotated
+    pub max_size_mb: u64,
+    /// Number of rotated log files kept
+    pub max_files: u32,
+    /// Also write to stderr, which browsers show in their own logs
+    pub stderr: bool,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            level: Level::Info,
+            file: true,
+            dir: log::default_dir(),
+            max_size_mb: 1,
+            max_files: 3,
+            stderr: false,
+        }
+    }
+}
+
+impl LogConfig {
+    /// Logger of the helper serving the browser
+    pub fn logger(&self) -> Logger {
+        let file = self.file.then(|| {
+            RotatingFile::new(
+                self.dir.join("fbrowserhelper.log"),
+                self.max_size_mb.saturating_mul(BYTES_PER_MB),
+                self.max_files,
+            )
+        });
+        Logger::new(self.level, file, self.stderr)
+    }
+
+    /// Logger of the command line subcommands, which only logs to stderr
+    ///
+    /// Installing or checking doesn't belong in the log of the helpers, the
+    /// subcommands report to the terminal themselves.
+    pub fn cli_logger(&self) -> Logger {
+        Logger::new(self.level, None, self.stderr)
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(std::io::Error),
+    Parse(toml::de::Error),
+    Invalid(String),
+}
+
+// Allow IOError to be converted to ConfigError
+impl From<std::io::Error> for ConfigError {
+    fn from(err: std::io::Error) -> Self {
+        ConfigError::Io(err)
+    }
+}
+
+// Allow TOML errors to be converted to ConfigError
+impl From<toml::de::Error> for ConfigError {
+    fn from(err: toml::de::Error) -> Self {
+        ConfigError::Parse(err)
+    }
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Io(err) => write!(f, "Failed to read config: {}", err),
+            ConfigError::Parse(err) => write!(f, "Invalid config: {}", err.message()),
+            ConfigError::Invalid(message) => write!(f, "Invalid config: {}", message),
+        }
+    }
+}
+
+impl Config {
+    /// Per-user config file, e.g. `$XDG_CONFIG_HOME/fbrowserhelper/config.toml`
+    /// on Linux and `%APPDATA%\fbrowserhelper\config.toml` on Windows
+    pub fn default_path() -> Option<PathBuf> {
+        Some(
+            dirs::config_dir()?
+                .join("fbrowserhelper")
+                .join("config.toml"),
+        )
+    }
+
+    /// Read the config, apply the environment variables and validate the
+    /// result, a missing file gives the defaults
+    pub fn load(path: &Path) -> Result<Config, ConfigError> {
+        let config = match std::fs::read_to_string(path) {
+            Ok(text) => Config::parse(&text)?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Config::default(),
+            Err(err) => return Err(err.into()),
+        };
+        config.with_env_overrides()?.validated()
+    }
+
+    pub fn parse(text: &str) -> Result<Config, ConfigError> {
+        Ok(toml::from_str(text)?)
+    }
+
+    pub fn to_toml(&self) -> String {
+        toml::to_string_pretty(self).unwrap_or_default()
+    }
+
+    /// Apply the `FBROWSERHELPER_FAVICON_SERVICE`,
+    /// `FBROWSERHELPER_MAX_MESSAGE_SIZE` and `FBROWSERHELPER_LOG` environment
+    /// variables
+    pub fn with_env_overrides(self) -> Result<Config, ConfigError> {
+        self.with_overrides(|name| std::env::var(name).ok())
+    }
+
+    /// Apply the variables given by `var`, values that don't parse are errors
+    /// like invalid values in the file
+    fn with_overrides(
+        mut self,
+        var: impl Fn(&str) -> Option<String>,
+    ) -> Result<Config, ConfigError> {
+        let invalid = |name: &str, value: &str| {
+            ConfigError::Invalid(format!("{} has an invalid value {:?}", name, value))
+        };
+
+        if let Some(url_template) = var("FBROWSERHELPER_FAVICON_SERVICE") {
+            self.favicon.service_url = Some(url_template);
+        }
+        if let Some(size) = var("FBROWSERHELPER_MAX_MESSAGE_SIZE") {
+            self.messaging.max_message_size = size
+                .trim()
+                .parse()
+                .map_err(|_| invalid("FBROWSERHELPER_MAX_MESSAGE_SIZE", &size))?;
+        }
+        if let Some(level) = var("FBROWSERHELPER_LOG") {
+            self.log.level = level
+                .parse()
+                .map_err(|_| invalid("FBROWSERHELPER_LOG", &level))?;
+        }
+        Ok(self)
+    }
+
+    /// The config if it is valid
+    pub fn validated(self) -> Result<Config, ConfigError> {
+        self.validate()?;
+        Ok(self)
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |message: &str| Err(ConfigError::Invalid(message.into()));
+
+        if let Some(service_url) = &self.favicon.service_url {
+            if !service_url.contains("{origin}") && !service_url.contains("{domain}") {
+                return invalid("favicon.service_url must contain {origin} or {domain}");
+            }
+            if url::Url::parse(&service_url.replace(['{', '}'], "")).is_err() {
+                return invalid("favicon.service_url is not a valid URL");
+            }
+        }
+        if self.favicon.cache_dir.as_os_str().is_empty() {
+            return invalid("favicon.cache_dir must not be empty");
+        }
+        if self
+            .favicon
+            .cache_ttl_days
+            .checked_mul(SECS_PER_DAY)
+            .is_none()
+        {
+            return invalid("favicon.cache_ttl_days is too large");
+        }
+        if self
+            .favicon
+            .cache_max_size_mb
+            .checked_mul(BYTES_PER_MB)
+            .is_none()
+        {
+            return invalid("favicon.cache_max_size_mb is too large");
+        }
+        if self.window.icon_sizes.is_empty() {
+            return invalid("window.icon_sizes must not be empty");
+        }
+        if self
+            .window
+            .icon_sizes
+            .iter()
+            .any(|size| !(1..=256).contains(size))
+        {
+            return invalid("window.icon_sizes must be between 1 and 256");
+        }
+        if self.browser.process_names.iter().any(String::is_empty) {
+            return invalid("browser.process_names must not contain empty names");
+        }
+        if self.messaging.max_message_size == 0 {
+            return invalid("messaging.max_message_size must be greater than 0");
+        }
+        if self.log.file && self.log.dir.as_os_str().is_empty() {
+            return invalid("log.dir must not be empty");
+        }
+        if self.log.file && self.log.max_size_mb == 0 {
+            return invalid("log.max_size_mb must be greater than 0");
+        }
+        if self.log.max_size_mb.checked_mul(BYTES_PER_MB).is_none() {
+            return invalid("log.max_size_mb is too large");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_defaults() {
+        let config = Config::parse("").unwrap();
+        assert_eq!(config, Config::default());
+        assert!(config.validate().is_ok());
+        assert!(config
+            .browser
+            .is_browser_process("C:\\Program Files\\firefox.exe"));
+        assert!(config
+            .browser
+            .is_browser_process("/usr/lib/firefox/firefox-bin"));
+        assert!(!config.browser.is_browser_process("/usr/bin/code"));
+    }
+
+    #[test]
+    fn test_missing_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config::load(&dir.path().join("config.toml")).unwrap();
+        assert_eq!(config, Config::default());
+    }
+
+    #[test]
+    fn test_partial_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        std::fs::write(
+            &path,
+            r#"
+            [favicon]
+            service_url = "https://icons.example.com/{domain}.ico"
+
+            [window]
+            icon_sizes = [32, 64]
+            "#,
+        )
+        .unwrap();
+
+        let config = Config::load(&path).unwrap();
+        assert_eq!(
+            config.favicon.service_url.as_deref(),
+            Some("https://icons.example.com/{domain}.ico")
+        );
+        assert_eq!(config.window.icon_sizes, [32, 64]);
+        assert_eq!(config.log.level, Level::Info);
+        assert_eq!(config.browser, BrowserConfig::default());
+        assert_eq!(config.favicon.cache_dir, FaviconCache::default_dir());
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let config = Config::default();
+        assert_eq!(Config::parse(&config.to_toml()).unwrap(), config);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(matches!(
+            Config::parse("[favicon]\nunknown = 1"),
+            Err(ConfigError::Parse(_))
+        ));
+        assert!(matches!(
+            Config::parse("[window]\nicon_sizes = \"64\""),
+            Err(ConfigError::Parse(_))
+        ));
+        assert!(matches!(
+            Config::parse("[log]\nlevel = \"verbose\""),
+            Err(ConfigError::Parse(_))
+        ));
+
+        let invalid = [
+            "[favicon]\nservice_url = \"https://icons.example.com/\"",
+            "[window]\nicon_sizes = []",
+            "[window]\nicon_sizes = [0, 64]",
+            "[window]\nicon_sizes = [512]",
+            "[browser]\nprocess_names = [\"\"]",
+            "[messaging]\nmax_message_size = 0",
+            "[log]\nmax_size_mb = 0",
+            "[favicon]\ncache_ttl_days = 9223372036854775807",
+            "[favicon]\ncache_max_size_mb = 9223372036854775807",
+            "[log]\nmax_size_mb = 9223372036854775807",
+        ];
+        for text in invalid {
+            let config = Config::parse(text).unwrap();
+            assert!(
+                matches!(config.validate(), Err(ConfigError::Invalid(_))),
+                "{}",
+                text
+            );
+        }
+
+        // Overrides are validated like the file
+        let env = |vars: &'static [(&'static str, &'static str)]| {
+            move |name: &str| {
+                vars.iter()
+                    .find(|(var, _)| *var == name)
+                    .map(|(_, value)| value.to_string())
+            }
+        };
+        let config = Config::default()
+            .with_overrides(env(&[
+                ("FBROWSERHELPER_MAX_MESSAGE_SIZE", "2048"),
+                ("FBROWSERHELPER_LOG", "debug"),
+            ]))
+            .unwrap();
+        assert_eq!(config.messaging.max_message_size, 2048);
+        assert_eq!(config.log.level, Level::Debug);
+        let err = Config::default()
+            .with_overrides(env(&[("FBROWSERHELPER_LOG", "verbose")]))
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid config: FBROWSERHELPER_LOG has an invalid value \"verbose\""
+        );
+        assert!(Config::default()
+            .with_overrides(env(&[("FBROWSERHELPER_MAX_MESSAGE_SIZE", "abc")]))
+            .is_err());
+        let config = Config::default()
+            .with_overrides(env(&[("FBROWSERHELPER_MAX_MESSAGE_SIZE", "0")]))
+            .unwrap();
+        assert!(config.validated().is_err());
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        std::fs::write(&path, "[window]\nicon_sizes = []").unwrap();
+        let err = Config::load(&path).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid config: window.icon_sizes must not be empty"
+        );
+    }
+}
@@ -1,18 +1,31 @@
-use std::io::Read;
+use std::sync::OnceLock;
 
 use ico::IconImage;
+use image::imageops::{self, FilterType};
+use image::RgbaImage;
+use reqwest::blocking::Client;
+use sha2::{Digest, Sha256};
 use url::Url;
 
-use crate::log;
+use crate::utils::favicon_cache::{CacheMeta, FaviconCache};
+use crate::utils::favicon_provider::{
+    is_not_modified, FaviconProvider, FaviconRequest, LinkTagFaviconProvider,
+    ServiceFaviconProvider, SiteFaviconProvider, TabFaviconProvider,
+};
+use crate::utils::log::{log_at, Level};
 
-#[allow(dead_code)]
 #[derive(Debug)]
 pub enum GetFaviconError {
     UrlDomainError,
-    NotInPngFormatError,
+    DataUrlError,
+    NotFound,
     ReqwestError(reqwest::Error),
     IOError(std::io::Error),
-    LodepngError(lodepng::Error),
+    ImageError(image::ImageError),
+    SvgError(resvg::usvg::Error),
+    /// Rendered SVG could not be turned into an image or is blank, e.g. its
+    /// text had no font
+    SvgRenderError,
 }
 
 // Allow IOError to be converted to GetFaviconError
@@ -29,39 +42,609 @@ impl From<reqwest::Error> for GetFaviconError {
     }
 }
 
-// Allow LodepngError to be converted to GetFaviconError
-impl From<lodepng::Error> for GetFaviconError {
-    fn from(error: lodepng::Error) -> Self {
-        GetFaviconError::LodepngError(error)
+// Allow ImageError to be converted to GetFaviconError
+impl From<image::ImageError> for GetFaviconError {
+    fn from(error: image::ImageError) -> Self {
+        GetFaviconError::ImageError(error)
     }
 }
 
-/// Get the favicon from a URL
+// Allow SvgError to be converted to GetFaviconError
+impl From<resvg::usvg::Error> for GetFaviconError {
+    fn from(error: resvg::usvg::Error) -> Self {
+        GetFaviconError::SvgError(error)
+    }
+}
+
+/// Chain of favicon providers, tried in order until one succeeds
+pub struct FaviconProviders {
+    client: Client,
+    providers: Vec<Box<dyn FaviconProvider>>,
+    cache: FaviconCache,
+}
+
+impl Default for FaviconProviders {
+    /// Providers that only contact the site itself
+    fn default() -> Self {
+        FaviconProviders::new(
+            vec![
+                Box::new(TabFaviconProvider),
+                Box::new(SiteFaviconProvider),
+                Box::new(LinkTagFaviconProvider),
+            ],
+            FaviconCache::default(),
+        )
+    }
+}
+
+impl FaviconProviders {
+    pub fn new(providers: Vec<Box<dyn FaviconProvider>>, cache: FaviconCache) -> Self {
+        let client = Client::builder()
+            .timeout(std::time::Duration::from_secs(10))
+            .build()
+            .unwrap_or_default();
+        FaviconProviders {
+            client,
+            providers,
+            cache,
+        }
+    }
+
+    pub fn with_cache(mut self, cache: FaviconCache) -> Self {
+        self.cache = cache;
+        self
+    }
+
+    /// Add a third-party favicon service as the last provider
+    pub fn with_service(mut self, url_template: &str) -> Self {
+        self.providers.push(Box::new(ServiceFaviconProvider {
+            url_template: url_template.into(),
+        }));
+        self
+    }
+
+    /// Get the favicon from a URL
+    ///
+    /// Uses only the domain part for caching, the `favicon_url` is the one
+    /// browser knows, if any. Expired icons are revalidated with the server
+    /// and used as is if no provider succeeds.
+    pub fn get_favicon_from_url(
+        &self,
+        url: &Url,
+        favicon_url: Option<&str>,
+    ) -> Result<String, GetFaviconError> {
+        let domain = url.domain().ok_or(GetFaviconError::UrlDomainError)?;
+
+        let cached = self.cache.get(domain);
+        if let Some(entry) = &cached {
+            if !self.cache.is_expired(&entry.meta) {
+                return Ok(entry.path.to_string_lossy().into_owned());
+            }
+            if let Some(source_url) = &entry.meta.source_url {
+                if is_not_modified(
+                    &self.client,
+                    source_url,
+                    entry.meta.etag.as_deref(),
+                    entry.meta.last_modified.as_deref(),
+                ) {
+                    self.cache.refresh(domain);
+                    return Ok(entry.path.to_string_lossy().into_owned());
+                }
+            }
+        }
+
+        let request = FaviconRequest {
+            page_url: url,
+            favicon_url,
+        };
+        for provider in &self.providers {
+            let fetched = provider.fetch(&self.client, &request).and_then(|favicon| {
+                let image = decode_image(&favicon.bytes, None)?;
+                Ok((favicon, image))
+            });
+            match fetched {
+                Ok((favicon, image)) => {
+                    let meta = CacheMeta {
+                        source_url: favicon.source_url.map(String::from),
+                        etag: favicon.etag,
+                        last_modified: favicon.last_modified,
+                        ..Default::default()
+                    };
+                    let path = self.cache.insert(domain, &encode_ico(&image)?, meta)?;
+                    return Ok(path.to_string_lossy().into_owned());
+                }
+                Err(err) => {
+                    log_at(
+                        Level::Debug,
+                        &format!(
+                            "Favicon provider {} failed for {}: {:?}",
+                            provider.name(),
+                            domain,
+                            err
+                        ),
+                    );
+                }
+            }
+        }
+
+        // Stale icon is better than none, e.g. when offline
+        match cached {
+            Some(entry) => Ok(entry.path.to_string_lossy().into_owned()),
+            None => Err(GetFaviconError::NotFound),
+        }
+    }
+
+    /// Get the favicon from image bytes
+    ///
+    /// The icon is cached by the SHA-256 hash of the bytes, `mime_type` is
+    /// used to pick the decoder when given.
+    pub fn get_favicon_from_bytes(
+        &self,
+        bytes: &[u8],
+        mime_type: Option<&str>,
+    ) -> Result<String, GetFaviconError> {
+        let hash = Sha256::digest(bytes)
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect::<String>();
+
+        // Content never changes for the same hash, so expiry does not matter
+        if let Some(entry) = self.cache.get(&hash) {
+            return Ok(entry.path.to_string_lossy().into_owned());
+        }
+
+        let image = decode_image(bytes, mime_type)?;
+        let path = self
+            .cache
+            .insert(&hash, &encode_ico(&image)?, CacheMeta::default())?;
+        Ok(path.to_string_lossy().into_owned())
+    }
+}
+
+/// Decode image bytes, the format is guessed if MIME type is not known
 ///
-/// Uses only the domain part and queries the icon from Google
-pub fn get_favicon_from_url(url: &Url) -> Result<String, GetFaviconError> {
-    let domain = url.domain().ok_or(GetFaviconError::UrlDomainError)?;
-    let url_without_path = format!("{}://{}", url.scheme(), domain);
+/// SVG images are rasterized to the largest icon size.
+pub fn decode_image(
+    bytes: &[u8],
+    mime_type: Option<&str>,
+) -> Result<image::DynamicImage, GetFaviconError> {
+    let is_svg = match mime_type {
+        Some(mime_type) => mime_type == "image/svg+xml",
+        None => looks_like_svg(bytes),
+    };
+    if is_svg {
+        return Ok(rasterize_svg(bytes, ICO_SIZES[ICO_SIZES.len() - 1])?.into());
+    }
 
-    let icon_file = format!("{}.ico", domain);
+    match mime_type.and_then(image::ImageFormat::from_mime_type) {
+        Some(format) => Ok(image::load_from_memory_with_format(bytes, format)?),
+        None => Ok(image::load_from_memory(bytes)?),
+    }
+}
 
-    // Check if the icon file already exists
-    if std::fs::metadata(&icon_file).is_ok() {
-        return Ok(icon_file);
+/// Whether the root element is `<svg`, after the XML declaration, doctype,
+/// comments and processing instructions
+fn looks_like_svg(bytes: &[u8]) -> bool {
+    let start = String::from_utf8_lossy(&bytes[..bytes.len().min(1024)]).to_ascii_lowercase();
+    let mut rest = start.trim_start_matches('\u{feff}');
+    loop {
+        rest = rest.trim_start();
+        let end = if rest.starts_with("<?") {
+            rest.find("?>").map(|end| end + 2)
+        } else if rest.starts_with("<!--") {
+            rest.find("-->").map(|end| end + 3)
+        } else if rest.starts_with("<!doctype") {
+            // Internal subset in brackets may contain `>`
+            match (rest.find('['), rest.find('>')) {
+                (Some(open), Some(end)) if open < end => rest.find("]>").map(|end| end + 2),
+                (_, end) => end.map(|end| end + 1),
+            }
+        } else {
+            break;
+        };
+        match end {
+            Some(end) => rest = &rest[end..],
+            None => return false,
+        }
     }
+    rest.strip_prefix("<svg")
+        .and_then(|rest| rest.chars().next())
+        .is_some_and(|next| next.is_whitespace() || next == '>' || next == '/')
+}
+
+/// SVG options with the system fonts, loaded once
+///
+/// Emoji favicons are a `<text>` element, which renders nothing without fonts.
+fn svg_options() -> &'static resvg::usvg::Options<'static> {
+    static OPTIONS: OnceLock<resvg::usvg::Options<'static>> = OnceLock::new();
+    OPTIONS.get_or_init(|| {
+        let mut options = resvg::usvg::Options::default();
+        let fontdb = options.fontdb_mut();
+        fontdb.load_system_fonts();
+
+        // Defaults like Times New Roman and Arial are often missing on
+        // Linux, any installed font is better than blank text
+        let installed = |fontdb: &resvg::usvg::fontdb::Database, family: &str| {
+            fontdb
+                .faces()
+                .any(|face| face.families.iter().any(|(name, _)| name == family))
+        };
+        let Some(fallback) = fontdb
+            .faces()
+            .find_map(|face| face.families.first())
+            .map(|(name, _)| name.clone())
+        else {
+            return options;
+        };
+        let serif = fontdb.family_name(&resvg::usvg::fontdb::Family::Serif);
+        if !installed(fontdb, serif) {
+            fontdb.set_serif_family(fallback.clone());
+        }
+        let sans_serif = fontdb.family_name(&resvg::usvg::fontdb::Family::SansSerif);
+        if !installed(fontdb, sans_serif) {
+            fontdb.set_sans_serif_family(fallback.clone());
+        }
+        if !installed(options.fontdb.as_ref(), &options.font_family) {
+            options.font_family = fallback;
+        }
+        options
+    })
+}
 
-    // Fetch the icon and convert to ico before saving
-    let icon = reqwest::blocking::get(format!("https://t2.gstatic.com/faviconV2?client=SOCIAL&type=FAVICON&fallback_opts=TYPE,SIZE,URL&url={}&size=128", url_without_path))?;
+/// Render the SVG so that its longer side is `size` pixels
+///
+/// Size comes from the `viewBox` or `width` and `height` attributes, areas
+/// not painted by the SVG stay transparent. A blank result is an error, so
+/// that the next provider is tried instead of caching it.
+fn rasterize_svg(bytes: &[u8], size: u32) -> Result<RgbaImage, GetFaviconError> {
+    let tree = resvg::usvg::Tree::from_data(bytes, svg_options())?;
+    let svg_size = tree.size();
+    let scale = size as f32 / svg_size.width().max(svg_size.height());
+    let width = ((svg_size.width() * scale).round() as u32).clamp(1, size);
+    let height = ((svg_size.height() * scale).round() as u32).clamp(1, size);
 
-    let image = image::load_from_memory(icon.bytes()?.to_vec().as_slice()).unwrap();
-    let width = image.width();
-    let height = image.height();
-    let bytevector = image.to_rgba8().into_vec();
+    let mut pixmap =
+        resvg::tiny_skia::Pixmap::new(width, height).ok_or(GetFaviconError::SvgRenderError)?;
+    resvg::render(
+        &tree,
+        resvg::tiny_skia::Transform::from_scale(scale, scale),
+        &mut pixmap.as_mut(),
+    );
+    if pixmap.pixels().iter().all(|pixel| pixel.alpha() == 0) {
+        return Err(GetFaviconError::SvgRenderError);
+    }
 
-    let icondata = IconImage::from_rgba_data(width as u32, height as u32, bytevector);
+    // Pixmap is premultiplied, image expects straight alpha
+    let rgba = pixmap
+        .pixels()
+        .iter()
+        .flat_map(|pixel| {
+            let color = pixel.demultiply();
+            [color.red(), color.green(), color.blue(), color.alpha()]
+        })
+        .collect();
+    RgbaImage::from_raw(width, height, rgba).ok_or(GetFaviconError::SvgRenderError)
+}
+
+/// Sizes of the images in generated ico files
+pub const ICO_SIZES: [u32; 8] = [16, 20, 24, 32, 48, 64, 128, 256];
+
+/// Convert the image to ico with an entry for each of the `ICO_SIZES`
+fn encode_ico(image: &image::DynamicImage) -> Result<Vec<u8>, GetFaviconError> {
+    let image = image.to_rgba8();
 
     let mut icon_dir = ico::IconDir::new(ico::ResourceType::Icon);
-    icon_dir.add_entry(ico::IconDirEntry::encode(&icondata)?);
-    icon_dir.write(std::fs::File::create(&icon_file)?)?;
-    Ok(icon_file)
+    for size in ICO_SIZES {
+        let icondata = IconImage::from_rgba_data(size, size, resize_icon(&image, size).into_raw());
+        icon_dir.add_entry(ico::IconDirEntry::encode(&icondata)?);
+    }
+    let mut ico = Vec::new();
+    icon_dir.write(&mut ico)?;
+    Ok(ico)
+}
+
+/// Largest icon that is upscaled with nearest-neighbour at any factor
+const TINY_ICON_SIZE: u32 = 16;
+
+/// Scale the image to a square of given size, keeping the aspect ratio
+///
+/// Downscaling uses Lanczos filter. Upscaling uses nearest-neighbour for
+/// whole factors and tiny icons, so that they stay sharp instead of getting
+/// blurry, and Catmull-Rom otherwise, which doesn't duplicate uneven rows and
+/// columns. Non-square images are centered on a transparent background.
+pub fn resize_icon(image: &RgbaImage, size: u32) -> RgbaImage {
+    let (width, height) = image.dimensions();
+    let scale = size as f64 / width.max(height) as f64;
+    let new_width = ((width as f64 * scale).round() as u32).clamp(1, size);
+    let new_height = ((height as f64 * scale).round() as u32).clamp(1, size);
+
+    let resized = if (new_width, new_height) == (width, height) {
+        image.clone()
+    } else if scale < 1.0 {
+        imageops::resize(image, new_width, new_height, FilterType::Lanczos3)
+    } else if size.is_multiple_of(width.max(height)) || width.max(height) <= TINY_ICON_SIZE {
+        imageops::resize(image, new_width, new_height, FilterType::Nearest)
+    } else {
+        imageops::resize(image, new_width, new_height, FilterType::CatmullRom)
+    };
+    if (new_width, new_height) == (size, size) {
+        return resized;
+    }
+
+    let mut square = RgbaImage::new(size, size);
+    imageops::overlay(
+        &mut square,
+        &resized,
+        ((size - new_width) / 2).into(),
+        ((size - new_height) / 2).into(),
+    );
+    square
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::utils::favicon_cache::DEFAULT_MAX_SIZE;
+    use crate::utils::test_support::{png, serve};
+
+    fn localhost(base: &Url) -> Url {
+        Url::parse(&format!("http://localhost:{}/", base.port().unwrap())).unwrap()
+    }
+
+    #[test]
+    fn test_get_favicon_from_url_caches() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = FaviconCache::new(dir.path().into(), Duration::from_secs(60), DEFAULT_MAX_SIZE);
+        let favicons = FaviconProviders::new(vec![Box::new(SiteFaviconProvider)], cache);
+        let page_url = localhost(&serve(vec![("/favicon.ico", png(16, 16))]));
+
+        let path = favicons.get_favicon_from_url(&page_url, None).unwrap();
+        assert_eq!(path, dir.path().join("localhost.ico").to_string_lossy());
+        let icon_dir = ico::IconDir::read(std::fs::File::open(&path).unwrap()).unwrap();
+        assert_eq!(icon_dir.entries().len(), ICO_SIZES.len());
+        assert!(dir.path().join("localhost.json").exists());
+    }
+
+    #[test]
+    fn test_get_favicon_from_url_uses_stale_icon_offline() {
+        let dir = tempfile::tempdir().unwrap();
+        let favicons = |ttl| {
+            let cache = FaviconCache::new(dir.path().into(), ttl, DEFAULT_MAX_SIZE);
+            FaviconProviders::new(vec![Box::new(SiteFaviconProvider)], cache)
+        };
+        let page_url = localhost(&serve(vec![("/favicon.ico", png(16, 16))]));
+        let path = favicons(Duration::ZERO)
+            .get_favicon_from_url(&page_url, None)
+            .unwrap();
+
+        // Nothing listens on the port anymore
+        let offline_url = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            localhost(&Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap())
+        };
+        assert_eq!(
+            favicons(Duration::ZERO)
+                .get_favicon_from_url(&offline_url, None)
+                .unwrap(),
+            path
+        );
+    }
+
+    #[test]
+    fn test_get_favicon_from_bytes() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = FaviconCache::new(dir.path().into(), Duration::ZERO, DEFAULT_MAX_SIZE);
+        let favicons = FaviconProviders::new(vec![], cache);
+
+        let path = favicons
+            .get_favicon_from_bytes(&png(8, 8), Some("image/png"))
+            .unwrap();
+        assert!(path.ends_with(".ico"));
+        assert_eq!(
+            favicons.get_favicon_from_bytes(&png(8, 8), None).unwrap(),
+            path
+        );
+    }
+
+    /// Deterministic source image with hard edges and gradients
+    fn golden_source(size: u32) -> RgbaImage {
+        let (center, radius) = (size as i32 / 2, (size * 30 / 64) as i32);
+        RgbaImage::from_fn(size, size, |x, y| {
+            let alpha = if (x as i32 - center).pow(2) + (y as i32 - center).pow(2) < radius.pow(2) {
+                255
+            } else {
+                0
+            };
+            image::Rgba([
+                (x * 256 / size) as u8,
+                (y * 256 / size) as u8,
+                if x < y { 255 } else { 0 },
+                alpha,
+            ])
+        })
+    }
+
+    /// Compare with the golden image, or save it when `UPDATE_GOLDEN` is set
+    fn assert_golden(actual: &RgbaImage, name: &str) {
+        let golden_path = std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("testdata/favicon")
+            .join(name);
+        if std::env::var_os("UPDATE_GOLDEN").is_some() {
+            std::fs::create_dir_all(golden_path.parent().unwrap()).unwrap();
+            actual.save(&golden_path).unwrap();
+            return;
+        }
+        let golden = image::open(&golden_path).unwrap().to_rgba8();
+        assert_eq!(actual.dimensions(), golden.dimensions());
+
+        // Allow rounding differences between platforms
+        let matches = actual
+            .as_raw()
+            .iter()
+            .zip(golden.as_raw())
+            .all(|(a, b)| a.abs_diff(*b) <= 1);
+        assert!(matches, "{:?} differs", golden_path);
+    }
+
+    // Regenerate the golden images with `UPDATE_GOLDEN=1 cargo test`
+    #[test]
+    fn test_encode_ico_golden() {
+        let ico = encode_ico(&golden_source(64).into()).unwrap();
+        let icon_dir = ico::IconDir::read(std::io::Cursor::new(ico)).unwrap();
+        assert_eq!(icon_dir.entries().len(), ICO_SIZES.len());
+
+        for (entry, size) in icon_dir.entries().iter().zip(ICO_SIZES) {
+            assert_eq!((entry.width(), entry.height()), (size, size));
+            let decoded = entry.decode().unwrap();
+            let actual = RgbaImage::from_raw(size, size, decoded.rgba_data().to_vec()).unwrap();
+            assert_golden(&actual, &format!("golden_{}.png", size));
+        }
+    }
+
+    #[test]
+    fn test_resize_icon_golden_non_integer_upscale() {
+        let resized = resize_icon(&golden_source(48), 64);
+        assert_golden(&resized, "golden_upscale_48_64.png");
+
+        // Smooth, not rows and columns repeated unevenly
+        let rows = (0..64)
+            .map(|y| {
+                (0..64)
+                    .map(|x| *resized.get_pixel(x, y))
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        assert!(rows.windows(2).all(|pair| pair[0] != pair[1]));
+    }
+
+    #[test]
+    fn test_resize_icon_upscales_with_nearest_neighbour() {
+        let mut tiny = RgbaImage::new(2, 2);
+        tiny.put_pixel(0, 0, image::Rgba([255, 0, 0, 255]));
+        tiny.put_pixel(1, 1, image::Rgba([0, 0, 255, 255]));
+
+        let resized = resize_icon(&tiny, 16);
+        assert_eq!(resized.dimensions(), (16, 16));
+        assert_eq!(resized.get_pixel(7, 7).0, [255, 0, 0, 255]);
+        assert_eq!(resized.get_pixel(8, 7).0, [0, 0, 0, 0]);
+        assert_eq!(resized.get_pixel(15, 15).0, [0, 0, 255, 255]);
+    }
+
+    #[test]
+    fn test_resize_icon_keeps_aspect_ratio() {
+        let wide = RgbaImage::from_pixel(64, 32, image::Rgba([255, 0, 0, 255]));
+        let resized = resize_icon(&wide, 32);
+        assert_eq!(resized.dimensions(), (32, 32));
+        assert_eq!(resized.get_pixel(16, 7).0[3], 0);
+        assert_eq!(resized.get_pixel(16, 8).0, [255, 0, 0, 255]);
+        assert_eq!(resized.get_pixel(16, 23).0, [255, 0, 0, 255]);
+        assert_eq!(resized.get_pixel(16, 24).0[3], 0);
+    }
+
+    #[test]
+    fn test_svg_viewbox_scaling() {
+        // Left half is red, right half is not painted
+        let svg = br#"<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 10 10">
+            <rect width="5" height="10" fill="red"/>
+        </svg>"#;
+        let image = decode_image(svg, None).unwrap().to_rgba8();
+        assert_eq!(image.dimensions(), (256, 256));
+        assert_eq!(image.get_pixel(64, 128).0, [255, 0, 0, 255]);
+        assert_eq!(image.get_pixel(192, 128).0, [0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_svg_transparent_background_in_ico() {
+        // Wide SVG is centered in the square icon with transparent padding
+        let svg = br#"<?xml version="1.0"?>
+            <svg xmlns="http://www.w3.org/2000/svg" width="20" height="10" viewBox="0 0 2 1">
+            <rect width="2" height="1" fill="blue"/>
+        </svg>"#;
+        let image = decode_image(svg, Some("image/svg+xml")).unwrap();
+        assert_eq!((image.width(), image.height()), (256, 128));
+
+        let icon = resize_icon(&image.to_rgba8(), 32);
+        assert_eq!(icon.get_pixel(16, 0).0[3], 0);
+        assert_eq!(icon.get_pixel(16, 16).0, [0, 0, 255, 255]);
+        assert_eq!(icon.get_pixel(16, 31).0[3], 0);
+    }
+
+    #[test]
+    fn test_looks_like_svg() {
+        let svgs: [&[u8]; 6] = [
+            br#"<svg xmlns="http://www.w3.org/2000/svg"/>"#,
+            b"\xef\xbb\xbf  <svg>",
+            br#"<?xml version="1.0" encoding="UTF-8"?><svg>"#,
+            br#"<!DOCTYPE svg PUBLIC "-//W3C//DTD SVG 1.1//EN"
+                "http://www.w3.org/Graphics/SVG/1.1/DTD/svg11.dtd">
+            <svg version="1.1">"#,
+            br#"<!-- Generator: Adobe Illustrator, <svg> export -->
+            <svg>"#,
+            br#"<?xml version="1.0"?>
+            <?xml-stylesheet href="icon.css"?>
+            <!DOCTYPE svg [ <!ENTITY arrow "->"> ]>
+            <!-- Icon -->
+            <svg>"#,
+        ];
+        for svg in svgs {
+            assert!(looks_like_svg(svg), "{}", String::from_utf8_lossy(svg));
+        }
+
+        let not_svgs: [&[u8]; 4] = [
+            b"<!DOCTYPE html><html><svg></svg></html>",
+            b"<svgfoo>",
+            b"<!-- <svg> never closed",
+            b"\x89PNG\r\n",
+        ];
+        for not_svg in not_svgs {
+            assert!(
+                !looks_like_svg(not_svg),
+                "{}",
+                String::from_utf8_lossy(not_svg)
+            );
+        }
+    }
+
+    #[test]
+    fn test_svg_text() {
+        let svg = br#"<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 100 100">
+            <text y=".9em" font-size="90">A</text>
+        </svg>"#;
+        // Without system fonts the text is blank, which is never a success
+        match decode_image(svg, None) {
+            Ok(image) => assert!(image.to_rgba8().pixels().any(|pixel| pixel.0[3] > 0)),
+            Err(err) => assert!(matches!(err, GetFaviconError::SvgRenderError)),
+        }
+    }
+
+    #[test]
+    fn test_blank_svg() {
+        let svg = br#"<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 10 10">
+            <rect width="10" height="10" fill="none"/>
+        </svg>"#;
+        assert!(matches!(
+            decode_image(svg, None),
+            Err(GetFaviconError::SvgRenderError)
+        ));
+    }
+
+    #[test]
+    fn test_invalid_svg() {
+        assert!(matches!(
+            decode_image(b"<svg", Some("image/svg+xml")),
+            Err(GetFaviconError::SvgError(_))
+        ));
+    }
+
+    #[test]
+    fn test_decode_image() {
+        let bytes = png(4, 2);
+        let image = decode_image(&bytes, Some("image/png")).unwrap();
+        assert_eq!((image.width(), image.height()), (4, 2));
+        assert!(decode_image(&bytes, None).is_ok());
+        assert!(decode_image(&bytes, Some("image/jpeg")).is_err());
+        assert!(decode_image(&[1, 2, 3], None).is_err());
+    }
 }
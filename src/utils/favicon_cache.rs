@@ -0,0 +1,303 @@
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::utils::log::{log_at, Level};
+
+/// Default time after which cached icons are fetched again
+pub const DEFAULT_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Default maximum total size of the cached icons
+pub const DEFAULT_MAX_SIZE: u64 = 50 * 1024 * 1024;
+
+/// Metadata stored next to each cached icon as `<key>.json`
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct CacheMeta {
+    /// Unix time in seconds when the icon was fetched or revalidated
+    pub fetched_at: u64,
+    /// Unix time in seconds when the icon was last used, for LRU eviction
+    pub last_used: u64,
+    pub source_url: Option<String>,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    /// Size of the icon file in bytes
+    pub size: u64,
+}
+
+pub struct CacheEntry {
+    pub path: PathBuf,
+    pub meta: CacheMeta,
+}
+
+/// Favicon cache in a directory, with expiry and size limit
+///
+/// Icons are stored as `<key>.ico`, the key is a domain or a content hash.
+pub struct FaviconCache {
+    dir: PathBuf,
+    ttl: Duration,
+    max_size: u64,
+}
+
+impl Default for FaviconCache {
+    fn default() -> Self {
+        FaviconCache::new(FaviconCache::default_dir(), DEFAULT_TTL, DEFAULT_MAX_SIZE)
+    }
+}
+
+impl FaviconCache {
+    pub fn new(dir: PathBuf, ttl: Duration, max_size: u64) -> Self {
+        FaviconCache { dir, ttl, max_size }
+    }
+
+    /// Per-user cache directory, e.g. `$XDG_CACHE_HOME/fbrowserhelper/favicons`
+    /// on Linux and `%LOCALAPPDATA%\fbrowserhelper\favicons` on Windows
+    pub fn default_dir() -> PathBuf {
+        dirs::cache_dir()
+            .unwrap_or_else(std::env::temp_dir)
+            .join("fbrowserhelper")
+            .join("favicons")
+    }
+
+    /// Get the entry and mark it used, expired entries are returned too
+    pub fn get(&self, key: &str) -> Option<CacheEntry> {
+        let path = self.icon_path(key);
+        if !path.is_file() {
+            return None;
+        }
+        let mut meta = self.read_meta(key).unwrap_or_default();
+        meta.last_used = now();
+        self.write_meta(key, &meta);
+        Some(CacheEntry { path, meta })
+    }
+
+    pub fn is_expired(&self, meta: &CacheMeta) -> bool {
+        now().saturating_sub(meta.fetched_at) >= self.ttl.as_secs()
+    }
+
+    /// Mark the entry fetched now, e.g. after the server says it's not modified
+    pub fn refresh(&self, key: &str) {
+        if let Some(mut meta) = self.read_meta(key) {
+            meta.fetched_at = now();
+            self.write_meta(key, &meta);
+        }
+    }
+
+    /// Store the icon and evict least recently used icons over the size limit
+    pub fn insert(
+        &self,
+        key: &str,
+        icon: &[u8],
+        mut meta: CacheMeta,
+    ) -> Result<PathBuf, std::io::Error> {
+        std::fs::create_dir_all(&self.dir)?;
+        let path = self.icon_path(key);
+        write_atomic(&path, icon)?;
+
+        let now = now();
+        meta.fetched_at = now;
+        meta.last_used = now;
+        meta.size = icon.len() as u64;
+        self.write_meta(key, &meta);
+
+        self.evict(key);
+        Ok(path)
+    }
+
+    fn evict(&self, keep_key: &str) {
+        let Ok(dir) = std::fs::read_dir(&self.dir) else {
+            return;
+        };
+        let mut entries = dir
+            .filter_map(|entry| {
+                let path = entry.ok()?.path();
+                if path.extension()? != "ico" {
+                    return None;
+                }
+                let key = path.file_stem()?.to_str()?.to_string();
+                let meta = self.read_meta(&key).unwrap_or_else(|| CacheMeta {
+                    size: path.metadata().map(|m| m.len()).unwrap_or_default(),
+                    ..Default::default()
+                });
+                Some((key, meta))
+            })
+            .collect::<Vec<_>>();
+
+        let mut total_size = entries.iter().map(|(_, meta)| meta.size).sum::<u64>();
+        entries.sort_by_key(|(_, meta)| meta.last_used);
+        for (key, meta) in entries {
+            if total_size <= self.max_size {
+                break;
+            }
+            if key == keep_key {
+                continue;
+            }
+            log_at(Level::Debug, &format!("Evicting cached favicon {}", key));
+            let _ = std::fs::remove_file(self.icon_path(&key));
+            let _ = std::fs::remove_file(self.meta_path(&key));
+            total_size = total_size.saturating_sub(meta.size);
+        }
+    }
+
+    fn icon_path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{}.ico", sanitize_key(key)))
+    }
+
+    fn meta_path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", sanitize_key(key)))
+    }
+
+    fn read_meta(&self, key: &str) -> Option<CacheMeta> {
+        serde_json::from_slice(&std::fs::read(self.meta_path(key)).ok()?).ok()
+    }
+
+    fn write_meta(&self, key: &str, meta: &CacheMeta) {
+        if let Ok(json) = serde_json::to_vec(meta) {
+            if let Err(err) = write_atomic(&self.meta_path(key), &json) {
+                log_at(
+                    Level::Warn,
+                    &format!("Failed to write favicon cache metadata: {:?}", err),
+                );
+            }
+        }
+    }
+}
+
+/// Replace the file without truncating it in place
+///
+/// Workers and the helpers of other browsers share the cache, one of them may
+/// be loading the icon while another writes it. The contents are written to
+/// a temporary file in the same directory and renamed over the old one, so
+/// readers see either the old or the new file.
+fn write_atomic(path: &Path, contents: &[u8]) -> Result<(), std::io::Error> {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let mut tmp_name = path.as_os_str().to_owned();
+    tmp_name.push(format!(
+        ".{}-{}.tmp",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    let tmp_path = PathBuf::from(tmp_name);
+
+    let result =
+        std::fs::write(&tmp_path, contents).and_then(|()| std::fs::rename(&tmp_path, path));
+    if result.is_err() {
+        let _ = std::fs::remove_file(&tmp_path);
+    }
+    result
+}
+
+/// Keep keys usable as file names
+fn sanitize_key(key: &str) -> String {
+    key.chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' | '.' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_insert_and_get() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = FaviconCache::new(dir.path().into(), DEFAULT_TTL, DEFAULT_MAX_SIZE);
+        assert!(cache.get("example.com").is_none());
+
+        let meta = CacheMeta {
+            etag: Some("\"abc\"".into()),
+            ..Default::default()
+        };
+        let path = cache.insert("example.com", b"icon", meta).unwrap();
+        assert_eq!(path, dir.path().join("example.com.ico"));
+
+        let entry = cache.get("example.com").unwrap();
+        assert_eq!(std::fs::read(entry.path).unwrap(), b"icon");
+        assert_eq!(entry.meta.etag.as_deref(), Some("\"abc\""));
+        assert_eq!(entry.meta.size, 4);
+        assert!(!cache.is_expired(&entry.meta));
+    }
+
+    #[test]
+    fn test_expiry_and_refresh() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = FaviconCache::new(dir.path().into(), Duration::from_secs(60), DEFAULT_MAX_SIZE);
+        cache
+            .insert("example.com", b"icon", CacheMeta::default())
+            .unwrap();
+
+        let mut meta = cache.read_meta("example.com").unwrap();
+        meta.fetched_at -= 120;
+        cache.write_meta("example.com", &meta);
+        assert!(cache.is_expired(&cache.get("example.com").unwrap().meta));
+
+        cache.refresh("example.com");
+        assert!(!cache.is_expired(&cache.get("example.com").unwrap().meta));
+    }
+
+    #[test]
+    fn test_lru_eviction() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = FaviconCache::new(dir.path().into(), DEFAULT_TTL, 10);
+        cache.insert("a", b"aaaa", CacheMeta::default()).unwrap();
+        cache.insert("b", b"bbbb", CacheMeta::default()).unwrap();
+
+        // Make `a` the most recently used
+        let mut meta = cache.read_meta("b").unwrap();
+        meta.last_used -= 10;
+        cache.write_meta("b", &meta);
+
+        cache.insert("c", b"cccc", CacheMeta::default()).unwrap();
+        assert!(cache.get("a").is_some());
+        assert!(cache.get("b").is_none());
+        assert!(cache.get("c").is_some());
+        assert!(!dir.path().join("b.json").exists());
+    }
+
+    #[test]
+    fn test_insert_while_reading() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = FaviconCache::new(dir.path().into(), DEFAULT_TTL, DEFAULT_MAX_SIZE);
+        let icons = [vec![1u8; 64 * 1024], vec![2u8; 128 * 1024]];
+        let path = cache
+            .insert("example.com", &icons[0], CacheMeta::default())
+            .unwrap();
+
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                for n in 0..200 {
+                    let icon = &icons[n % 2];
+                    cache
+                        .insert("example.com", icon, CacheMeta::default())
+                        .unwrap();
+                }
+            });
+            // Never a partly written or truncated file
+            for _ in 0..200 {
+                let icon = std::fs::read(&path).unwrap();
+                assert!(icons.contains(&icon), "read {} bytes", icon.len());
+                assert!(cache.get("example.com").is_some());
+            }
+        });
+
+        let files = std::fs::read_dir(dir.path()).unwrap().count();
+        assert_eq!(files, 2, "temporary files are left");
+    }
+
+    #[test]
+    fn test_sanitize_key() {
+        assert_eq!(sanitize_key("example.com"), "example.com");
+        assert_eq!(sanitize_key("../x/y"), ".._x_y");
+    }
+}
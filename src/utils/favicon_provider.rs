@@ -0,0 +1,410 @@
+use base64::Engine;
+use reqwest::blocking::Client;
+use url::Url;
+
+use crate::utils::favicon::{decode_image, GetFaviconError};
+
+/// What is known about the favicon of a page
+pub struct FaviconRequest<'a> {
+    /// URL of the page
+    pub page_url: &'a Url,
+
+    /// Favicon URL the browser already knows, e.g. `tab.favIconUrl`
+    pub favicon_url: Option<&'a str>,
+}
+
+/// Favicon image bytes and where they came from
+#[derive(Debug, Default)]
+pub struct FetchedFavicon {
+    pub bytes: Vec<u8>,
+    /// HTTP URL the bytes were fetched from, `None` for `data:` URLs
+    pub source_url: Option<Url>,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+impl FetchedFavicon {
+    fn from_bytes(bytes: Vec<u8>) -> Self {
+        FetchedFavicon {
+            bytes,
+            ..Default::default()
+        }
+    }
+}
+
+/// Source of favicon image bytes
+pub trait FaviconProvider: Send + Sync {
+    /// Name used in logs
+    fn name(&self) -> &'static str;
+
+    /// Fetch the favicon, bytes must be in a format `decode_image` can decode
+    fn fetch(
+        &self,
+        client: &Client,
+        request: &FaviconRequest,
+    ) -> Result<FetchedFavicon, GetFaviconError>;
+}
+
+/// Uses the favicon URL given by the browser
+pub struct TabFaviconProvider;
+
+impl FaviconProvider for TabFaviconProvider {
+    fn name(&self) -> &'static str {
+        "tab"
+    }
+
+    fn fetch(
+        &self,
+        client: &Client,
+        request: &FaviconRequest,
+    ) -> Result<FetchedFavicon, GetFaviconError> {
+        let favicon_url = request.favicon_url.ok_or(GetFaviconError::NotFound)?;
+        if favicon_url.starts_with("data:") {
+            return decode_data_url(favicon_url)
+                .map(|(_, bytes)| FetchedFavicon::from_bytes(bytes));
+        }
+
+        // Browser internal URLs such as `chrome://favicon` can't be fetched
+        let url = Url::parse(favicon_url).map_err(|_| GetFaviconError::NotFound)?;
+        match url.scheme() {
+            "http" | "https" => fetch_bytes(client, url),
+            _ => Err(GetFaviconError::NotFound),
+        }
+    }
+}
+
+/// Fetches `/favicon.ico` from the site
+pub struct SiteFaviconProvider;
+
+impl FaviconProvider for SiteFaviconProvider {
+    fn name(&self) -> &'static str {
+        "site"
+    }
+
+    fn fetch(
+        &self,
+        client: &Client,
+        request: &FaviconRequest,
+    ) -> Result<FetchedFavicon, GetFaviconError> {
+        let url = request
+            .page_url
+            .join("/favicon.ico")
+            .map_err(|_| GetFaviconError::UrlDomainError)?;
+        fetch_bytes(client, url)
+    }
+}
+
+/// Fetches the page and uses its `<link rel="icon">` tags
+pub struct LinkTagFaviconProvider;
+
+impl FaviconProvider for LinkTagFaviconProvider {
+    fn name(&self) -> &'static str {
+        "link-tag"
+    }
+
+    fn fetch(
+        &self,
+        client: &Client,
+        request: &FaviconRequest,
+    ) -> Result<FetchedFavicon, GetFaviconError> {
+        let html = client
+            .get(request.page_url.clone())
+            .send()?
+            .error_for_status()?
+            .text()?;
+
+        // Try each icon in document order until one can be decoded
+        for href in find_icon_links(&html) {
+            let Ok(url) = request.page_url.join(&href) else {
+                continue;
+            };
+            let favicon = if url.scheme() == "data" {
+                decode_data_url(url.as_str()).map(|(_, bytes)| FetchedFavicon::from_bytes(bytes))
+            } else {
+                fetch_bytes(client, url)
+            };
+            if let Ok(favicon) = favicon {
+                if decode_image(&favicon.bytes, None).is_ok() {
+                    return Ok(favicon);
+                }
+            }
+        }
+        Err(GetFaviconError::NotFound)
+    }
+}
+
+/// Queries a third-party favicon service
+///
+/// In the URL template `{origin}` is replaced with the page origin, e.g.
+/// `https://example.com`, and `{domain}` with the page domain. For example
+/// Google's service is
+/// `https://t2.gstatic.com/faviconV2?client=SOCIAL&type=FAVICON&fallback_opts=TYPE,SIZE,URL&url={origin}&size=128`
+pub struct ServiceFaviconProvider {
+    pub url_template: String,
+}
+
+impl FaviconProvider for ServiceFaviconProvider {
+    fn name(&self) -> &'static str {
+        "service"
+    }
+
+    fn fetch(
+        &self,
+        client: &Client,
+        request: &FaviconRequest,
+    ) -> Result<FetchedFavicon, GetFaviconError> {
+        let domain = request
+            .page_url
+            .domain()
+            .ok_or(GetFaviconError::UrlDomainError)?;
+        let origin = request.page_url.origin().ascii_serialization();
+        let url = self
+            .url_template
+            .replace("{origin}", &origin)
+            .replace("{domain}", domain);
+        let url = Url::parse(&url).map_err(|_| GetFaviconError::UrlDomainError)?;
+        fetch_bytes(client, url)
+    }
+}
+
+fn fetch_bytes(client: &Client, url: Url) -> Result<FetchedFavicon, GetFaviconError> {
+    let response = client.get(url.clone()).send()?.error_for_status()?;
+    let header = |name| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(String::from)
+    };
+    let etag = header(reqwest::header::ETAG);
+    let last_modified = header(reqwest::header::LAST_MODIFIED);
+    Ok(FetchedFavicon {
+        bytes: response.bytes()?.to_vec(),
+        source_url: Some(url),
+        etag,
+        last_modified,
+    })
+}
+
+/// Ask the server whether the favicon fetched earlier is still valid
+///
+/// Returns `true` only if the server answers `304 Not Modified`.
+pub fn is_not_modified(
+    client: &Client,
+    url: &str,
+    etag: Option<&str>,
+    last_modified: Option<&str>,
+) -> bool {
+    if etag.is_none() && last_modified.is_none() {
+        return false;
+    }
+    let mut request = client.get(url);
+    if let Some(etag) = etag {
+        request = request.header(reqwest::header::IF_NONE_MATCH, etag);
+    }
+    if let Some(last_modified) = last_modified {
+        request = request.header(reqwest::header::IF_MODIFIED_SINCE, last_modified);
+    }
+    request
+        .send()
+        .map(|response| response.status() == reqwest::StatusCode::NOT_MODIFIED)
+        .unwrap_or(false)
+}
+
+/// Decode a `data:` URL into its MIME type and bytes
+pub fn decode_data_url(data_url: &str) -> Result<(String, Vec<u8>), GetFaviconError> {
+    let (header, data) = data_url
+        .strip_prefix("data:")
+        .and_then(|rest| rest.split_once(','))
+        .ok_or(GetFaviconError::DataUrlError)?;
+    let (mime_type, is_base64) = match header.strip_suffix(";base64") {
+        Some(mime_type) => (mime_type, true),
+        None => (header, false),
+    };
+
+    let bytes = if is_base64 {
+        base64::engine::general_purpose::STANDARD
+            .decode(data.trim())
+            .map_err(|_| GetFaviconError::DataUrlError)?
+    } else {
+        percent_encoding::percent_decode_str(data).collect()
+    };
+    Ok((mime_type.to_string(), bytes))
+}
+
+/// Find `href` values of `<link rel="icon">` tags in the HTML
+///
+/// Both `icon` and `apple-touch-icon` relations are accepted, icons come in
+/// document order.
+fn find_icon_links(html: &str) -> Vec<String> {
+    let lowercase = html.to_ascii_lowercase();
+    let mut links = Vec::new();
+    let mut position = 0;
+    while let Some(start) = lowercase[position..].find("<link") {
+        let start = position + start;
+        let Some(end) = lowercase[start..].find('>') else {
+            break;
+        };
+        let end = start + end;
+        let attributes = parse_attributes(&html[start + "<link".len()..end]);
+        let is_icon = attributes.iter().any(|(name, value)| {
+            name == "rel"
+                && value.split_ascii_whitespace().any(|rel| {
+                    rel.eq_ignore_ascii_case("icon") || rel.eq_ignore_ascii_case("apple-touch-icon")
+                })
+        });
+        if is_icon {
+            if let Some((_, href)) = attributes.into_iter().find(|(name, _)| name == "href") {
+                links.push(href);
+            }
+        }
+        position = end;
+    }
+    links
+}
+
+/// Parse HTML tag attributes, names are lowercased
+fn parse_attributes(tag: &str) -> Vec<(String, String)> {
+    let mut attributes = Vec::new();
+    let mut chars = tag.trim_end_matches('/').chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let name = std::iter::from_fn(|| chars.next_if(|c| !c.is_whitespace() && *c != '='))
+            .collect::<String>()
+            .to_ascii_lowercase();
+        if name.is_empty() {
+            if chars.next().is_none() {
+                break;
+            }
+            continue;
+        }
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        if chars.next_if_eq(&'=').is_none() {
+            attributes.push((name, String::new()));
+            continue;
+        }
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let value = match chars.next_if(|c| *c == '"' || *c == '\'') {
+            Some(quote) => {
+                let value = std::iter::from_fn(|| chars.next_if(|c| *c != quote)).collect();
+                chars.next();
+                value
+            }
+            None => std::iter::from_fn(|| chars.next_if(|c| !c.is_whitespace())).collect(),
+        };
+        attributes.push((name, value));
+    }
+    attributes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::test_support::{png, serve};
+
+    #[test]
+    fn test_decode_data_url() {
+        assert_eq!(
+            decode_data_url("data:image/png;base64,AQID").unwrap(),
+            ("image/png".into(), vec![1, 2, 3])
+        );
+        assert_eq!(
+            decode_data_url("data:image/svg+xml,%3Csvg%3E").unwrap(),
+            ("image/svg+xml".into(), b"<svg>".to_vec())
+        );
+        assert!(decode_data_url("data:image/png;base64").is_err());
+        assert!(decode_data_url("https://example.com/").is_err());
+    }
+
+    #[test]
+    fn test_find_icon_links() {
+        let html = r#"
+            <html><head>
+            <LINK REL="stylesheet" href="style.css">
+            <link rel="shortcut icon" href="/a.ico"/>
+            <link href='b.png' rel=icon sizes="32x32">
+            <link rel="apple-touch-icon" href="c.png">
+            </head></html>
+        "#;
+        assert_eq!(find_icon_links(html), vec!["/a.ico", "b.png", "c.png"]);
+    }
+
+    #[test]
+    fn test_tab_provider() {
+        let base = serve(vec![("/icon.png", png(4, 4))]);
+        let client = Client::new();
+        let favicon_url = base.join("icon.png").unwrap();
+        let request = FaviconRequest {
+            page_url: &base,
+            favicon_url: Some(favicon_url.as_str()),
+        };
+        assert_eq!(
+            TabFaviconProvider.fetch(&client, &request).unwrap().bytes,
+            png(4, 4)
+        );
+
+        let request = FaviconRequest {
+            page_url: &base,
+            favicon_url: Some("chrome://favicon/"),
+        };
+        assert!(TabFaviconProvider.fetch(&client, &request).is_err());
+    }
+
+    #[test]
+    fn test_site_provider() {
+        let base = serve(vec![("/favicon.ico", png(4, 4))]);
+        let page_url = base.join("some/page").unwrap();
+        let request = FaviconRequest {
+            page_url: &page_url,
+            favicon_url: None,
+        };
+        assert_eq!(
+            SiteFaviconProvider
+                .fetch(&Client::new(), &request)
+                .unwrap()
+                .bytes,
+            png(4, 4)
+        );
+    }
+
+    #[test]
+    fn test_link_tag_provider() {
+        let base = serve(vec![
+            (
+                "/page",
+                br#"<link rel="icon" href="missing.png"><link rel="icon" href="icons/ok.png">"#
+                    .to_vec(),
+            ),
+            ("/icons/ok.png", png(4, 4)),
+        ]);
+        let page_url = base.join("page").unwrap();
+        let request = FaviconRequest {
+            page_url: &page_url,
+            favicon_url: None,
+        };
+        assert_eq!(
+            LinkTagFaviconProvider
+                .fetch(&Client::new(), &request)
+                .unwrap()
+                .bytes,
+            png(4, 4)
+        );
+    }
+
+    #[test]
+    fn test_service_provider() {
+        let service = serve(vec![("/icon?domain=localhost", png(4, 4))]);
+        let provider = ServiceFaviconProvider {
+            url_template: format!("{}icon?domain={{domain}}", service),
+        };
+        let page_url = Url::parse("http://localhost/page").unwrap();
+        let request = FaviconRequest {
+            page_url: &page_url,
+            favicon_url: None,
+        };
+        assert_eq!(
+            provider.fetch(&Client::new(), &request).unwrap().bytes,
+            png(4, 4)
+        );
+    }
+}
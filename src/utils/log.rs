@@ -1,13 +1,214 @@
-#[cfg(debug_assertions)]
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::{Mutex, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+#[cfg(all(windows, debug_assertions))]
 extern "system" {
     fn OutputDebugStringW(lpOutputString: windows::core::PCWSTR);
 }
 
-/// Log to OutputDebugStringW
+/// Severity of a log message, messages above the configured level are dropped
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Level {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl std::str::FromStr for Level {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "off" => Ok(Level::Off),
+            "error" => Ok(Level::Error),
+            "warn" => Ok(Level::Warn),
+            "info" => Ok(Level::Info),
+            "debug" => Ok(Level::Debug),
+            _ => Err("Unknown log level"),
+        }
+    }
+}
+
+impl Level {
+    fn as_str(self) -> &'static str {
+        match self {
+            Level::Off => "OFF",
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+        }
+    }
+}
+
+/// Log file that is renamed to `<name>.1` when it grows over `max_size`,
+/// keeping `max_files` old files
 ///
-/// Use win32 executable DebugView to see the logs
-#[cfg(debug_assertions)]
-pub fn log(s: &str) {
+/// The helpers of other browsers append to and rotate the same file, so the
+/// file is checked and opened again for each line instead of trusting what
+/// this process last saw.
+pub struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    max_files: u32,
+    /// Last written file, kept for `flush`
+    file: Option<File>,
+}
+
+impl RotatingFile {
+    pub fn new(path: PathBuf, max_size: u64, max_files: u32) -> Self {
+        RotatingFile {
+            path,
+            max_size,
+            max_files,
+            file: None,
+        }
+    }
+
+    fn write_line(&mut self, line: &str) -> Result<(), std::io::Error> {
+        let size = match std::fs::metadata(&self.path) {
+            Ok(metadata) => metadata.len(),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => 0,
+            Err(err) => return Err(err),
+        };
+        if size > 0 && size + line.len() as u64 > self.max_size {
+            match self.rotate() {
+                Ok(()) => {}
+                // Another process rotated it first, write to its new file
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+                Err(err) => return Err(err),
+            }
+        }
+
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        // One write per line, so lines of the helpers of other browsers
+        // appending to the same file don't interleave
+        self.file.insert(file).write_all(line.as_bytes())
+    }
+
+    fn rotate(&mut self) -> Result<(), std::io::Error> {
+        self.file = None;
+        let rotated = |n: u32| {
+            let mut name = self.path.clone().into_os_string();
+            name.push(format!(".{}", n));
+            PathBuf::from(name)
+        };
+        if self.max_files == 0 {
+            return std::fs::remove_file(&self.path);
+        }
+        let _ = std::fs::remove_file(rotated(self.max_files));
+        for n in (1..self.max_files).rev() {
+            let _ = std::fs::rename(rotated(n), rotated(n + 1));
+        }
+        std::fs::rename(&self.path, rotated(1))
+    }
+
+    fn flush(&mut self) {
+        if let Some(file) = &mut self.file {
+            let _ = file.sync_data();
+        }
+    }
+}
+
+/// Writes log messages to the log file and stderr
+///
+/// Stdout is the native messaging channel, so it is never logged to.
+pub struct Logger {
+    level: Level,
+    file: Option<Mutex<RotatingFile>>,
+    stderr: bool,
+}
+
+impl Logger {
+    pub fn new(level: Level, file: Option<RotatingFile>, stderr: bool) -> Self {
+        Logger {
+            level,
+            file: file.map(Mutex::new),
+            stderr,
+        }
+    }
+
+    pub fn enabled(&self, level: Level) -> bool {
+        level != Level::Off && level <= self.level
+    }
+
+    pub fn log(&self, level: Level, s: &str) {
+        if !self.enabled(level) {
+            return;
+        }
+        let line = format!(
+            "{} {:5} [{}] {}\n",
+            format_timestamp(now()),
+            level.as_str(),
+            std::process::id(),
+            s
+        );
+        if let Some(file) = &self.file {
+            if let Ok(mut file) = file.lock() {
+                if let Err(err) = file.write_line(&line) {
+                    // Don't lose the message, stderr ends up in the browser's log
+                    if !self.stderr {
+                        eprint!("Failed to write log file: {}\n{}", err, line);
+                    }
+                }
+            }
+        }
+        if self.stderr {
+            eprint!("{}", line);
+        }
+    }
+
+    pub fn flush(&self) {
+        if let Some(file) = &self.file {
+            if let Ok(mut file) = file.lock() {
+                file.flush();
+            }
+        }
+        let _ = std::io::stderr().flush();
+    }
+}
+
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+
+/// Set the logger used by `log`, messages logged before are dropped
+///
+/// Only the first call has an effect.
+pub fn init(logger: Logger) {
+    let _ = LOGGER.set(logger);
+}
+
+/// Per-user log directory, e.g. `$XDG_STATE_HOME/fbrowserhelper` on Linux
+/// and `%LOCALAPPDATA%\fbrowserhelper\logs` on Windows
+pub fn default_dir() -> PathBuf {
+    match dirs::state_dir() {
+        Some(dir) => dir.join("fbrowserhelper"),
+        None => dirs::data_local_dir()
+            .unwrap_or_else(std::env::temp_dir)
+            .join("fbrowserhelper")
+            .join("logs"),
+    }
+}
+
+/// Log at the given level
+///
+/// In Windows debug builds messages are also sent to `OutputDebugStringW`,
+/// use win32 executable DebugView to see them.
+pub fn log_at(level: Level, s: &str) {
+    #[cfg(all(windows, debug_assertions))]
     unsafe {
         let notepad = format!("FBrowserHelper: {}\0", s)
             .encode_utf16()
@@ -15,8 +216,135 @@ pub fn log(s: &str) {
         let pw = windows::core::PCWSTR::from_raw(notepad.as_ptr());
         OutputDebugStringW(pw);
     }
+
+    if let Some(logger) = LOGGER.get() {
+        logger.log(level, s);
+    }
+}
+
+/// Log at the info level
+pub fn log(s: &str) {
+    log_at(Level::Info, s);
+}
+
+/// Make sure the logged messages are on disk, e.g. before exiting
+pub fn flush() {
+    if let Some(logger) = LOGGER.get() {
+        logger.flush();
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Format Unix time in seconds as UTC, e.g. `2024-03-01T12:00:00Z`
+fn format_timestamp(secs: u64) -> String {
+    let (days, secs) = (secs / 86400, secs % 86400);
+
+    // Days to civil date, from http://howardhinnant.github.io/date_algorithms.html
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
 }
 
-#[cfg(not(debug_assertions))]
-#[inline]
-pub fn log(_s: &str) {}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_timestamp() {
+        assert_eq!(format_timestamp(0), "1970-01-01T00:00:00Z");
+        assert_eq!(format_timestamp(951782400), "2000-02-29T00:00:00Z");
+        assert_eq!(format_timestamp(1709294400), "2024-03-01T12:00:00Z");
+        assert_eq!(format_timestamp(4102444799), "2099-12-31T23:59:59Z");
+    }
+
+    #[test]
+    fn test_levels() {
+        let logger = Logger::new(Level::Warn, None, false);
+        assert!(logger.enabled(Level::Error));
+        assert!(logger.enabled(Level::Warn));
+        assert!(!logger.enabled(Level::Info));
+        assert!(!Logger::new(Level::Off, None, false).enabled(Level::Error));
+
+        assert_eq!("Debug".parse(), Ok(Level::Debug));
+        assert!("verbose".parse::<Level>().is_err());
+    }
+
+    #[test]
+    fn test_log_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("logs").join("fbrowserhelper.log");
+        let logger = Logger::new(
+            Level::Info,
+            Some(RotatingFile::new(path.clone(), 1024, 2)),
+            false,
+        );
+        logger.log(Level::Info, "Starting");
+        logger.log(Level::Debug, "Hidden");
+        logger.log(Level::Warn, "Careful");
+
+        let text = std::fs::read_to_string(&path).unwrap();
+        let lines = text.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].ends_with(&format!("INFO  [{}] Starting", std::process::id())));
+        assert!(lines[1].contains(" WARN  "));
+    }
+
+    #[test]
+    fn test_rotation() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("fbrowserhelper.log");
+        let mut file = RotatingFile::new(path.clone(), 10, 2);
+        for line in ["aaaaaa\n", "bbbbbb\n", "cccccc\n", "dddddd\n"] {
+            file.write_line(line).unwrap();
+        }
+
+        let read = |name: &str| std::fs::read_to_string(dir.path().join(name)).unwrap();
+        assert_eq!(read("fbrowserhelper.log"), "dddddd\n");
+        assert_eq!(read("fbrowserhelper.log.1"), "cccccc\n");
+        assert_eq!(read("fbrowserhelper.log.2"), "bbbbbb\n");
+        assert!(!dir.path().join("fbrowserhelper.log.3").exists());
+    }
+
+    #[test]
+    fn test_rotation_shared_by_processes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("fbrowserhelper.log");
+        let mut first = RotatingFile::new(path.clone(), 10, 8);
+        let mut second = RotatingFile::new(path.clone(), 10, 8);
+        let lines = (0..8).map(|n| format!("line-{}\n", n)).collect::<Vec<_>>();
+        for (n, line) in lines.iter().enumerate() {
+            let file = if n % 2 == 0 { &mut first } else { &mut second };
+            file.write_line(line).unwrap();
+        }
+
+        // Each rotation sees the lines of the other one, nothing is lost or
+        // written to a rotated file
+        let read = |name: String| std::fs::read_to_string(dir.path().join(name)).unwrap();
+        assert_eq!(read("fbrowserhelper.log".into()), lines[7]);
+        for n in 1..8 {
+            assert_eq!(read(format!("fbrowserhelper.log.{}", n)), lines[7 - n]);
+        }
+    }
+}
@@ -1,5 +1,15 @@
+pub mod config;
 pub mod favicon;
+pub mod favicon_cache;
+pub mod favicon_provider;
 pub mod log;
+pub mod native_manifest_doctor;
 pub mod native_manifest_installer;
 pub mod native_messaging;
+#[cfg(test)]
+pub mod test_support;
+#[cfg(windows)]
 pub mod win32;
+pub mod window_manager;
+#[cfg(target_os = "linux")]
+pub mod x11;
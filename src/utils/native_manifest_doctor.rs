@@ -0,0 +1,396 @@
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+
+use super::native_manifest_installer::{
+    installed_manifest_path, is_available, resolve_exe_path, same_file, Browser, InstallScope,
+    NativeManifestJson,
+};
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum CheckKind {
+    /// Browser finds the manifest
+    Registered,
+    /// Manifest is valid JSON with the fields browsers need
+    ValidJson,
+    /// `path` of the manifest is an existing executable
+    Executable,
+    /// `path` of the manifest is this executable, not a moved or other copy
+    CurrentExecutable,
+    /// Manifest allows the extension to connect
+    AllowedIds,
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct Check {
+    pub kind: CheckKind,
+    pub ok: bool,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum Status {
+    /// Manifest was found and checked
+    Installed,
+    NotInstalled,
+    /// Browser doesn't exist on this platform, e.g. Arc on Linux
+    Unavailable,
+}
+
+/// Result of checking the installation for one browser
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct BrowserReport {
+    pub browser: Browser,
+    pub status: Status,
+    pub manifest_path: Option<PathBuf>,
+    pub checks: Vec<Check>,
+}
+
+impl BrowserReport {
+    /// Whether the host is installed and works in the browser
+    pub fn ok(&self) -> bool {
+        self.status == Status::Installed && self.checks.iter().all(|check| check.ok)
+    }
+}
+
+/// Whether the installation works
+///
+/// With `all_required` every browser needs a working host, otherwise every
+/// installed host must work and there must be at least one.
+pub fn installation_ok(reports: &[BrowserReport], all_required: bool) -> bool {
+    let mut installed = reports
+        .iter()
+        .filter(|report| all_required || report.status == Status::Installed)
+        .peekable();
+    installed.peek().is_some() && installed.all(BrowserReport::ok)
+}
+
+/// Check the installation for each browser
+///
+/// The user installation is checked first, then the system one.
+pub fn diagnose(browsers: &[Browser], expected: &NativeManifestJson) -> Vec<BrowserReport> {
+    browsers
+        .iter()
+        .map(|&browser| {
+            if !is_available(browser) {
+                return BrowserReport {
+                    browser,
+                    status: Status::Unavailable,
+                    manifest_path: None,
+                    checks: vec![],
+                };
+            }
+            let manifest_path = [InstallScope::User, InstallScope::System]
+                .into_iter()
+                .find_map(|scope| {
+                    installed_manifest_path(browser, &expected.name, scope)
+                        .ok()
+                        .flatten()
+                });
+            diagnose_manifest(browser, expected, manifest_path.as_deref())
+        })
+        .collect()
+}
+
+/// Check the manifest found for the browser against the expected one
+pub fn diagnose_manifest(
+    browser: Browser,
+    expected: &NativeManifestJson,
+    manifest_path: Option<&Path>,
+) -> BrowserReport {
+    let mut checks = vec![];
+    let report = |checks| BrowserReport {
+        browser,
+        status: Status::Installed,
+        manifest_path: manifest_path.map(Path::to_path_buf),
+        checks,
+    };
+
+    let Some(manifest_path) = manifest_path else {
+        return BrowserReport {
+            status: Status::NotInstalled,
+            ..report(vec![])
+        };
+    };
+    checks.push(pass(
+        CheckKind::Registered,
+        format!("Manifest found at {}", manifest_path.display()),
+    ));
+
+    let manifest = std::fs::read(manifest_path)
+        .map_err(|err| format!("Failed to read manifest: {}", err))
+        .and_then(|bytes| {
+            serde_json::from_slice::<NativeManifestJson>(&bytes)
+                .map_err(|err| format!("Invalid manifest: {}", err))
+        });
+    let manifest = match manifest {
+        Ok(manifest) => manifest,
+        Err(message) => {
+            checks.push(fail(CheckKind::ValidJson, message));
+            return report(checks);
+        }
+    };
+    if manifest.name != expected.name {
+        checks.push(fail(
+            CheckKind::ValidJson,
+            format!(
+                "Manifest name is {:?}, expected {:?}",
+                manifest.name, expected.name
+            ),
+        ));
+    } else if manifest.type_ != "stdio" {
+        checks.push(fail(
+            CheckKind::ValidJson,
+            format!("Manifest type is {:?}, expected \"stdio\"", manifest.type_),
+        ));
+    } else {
+        checks.push(pass(CheckKind::ValidJson, "Manifest is valid"));
+    }
+
+    let exe_path = resolve_exe_path(manifest_path, &manifest);
+    if !exe_path.is_file() {
+        checks.push(fail(
+            CheckKind::Executable,
+            format!("{} does not exist", exe_path.display()),
+        ));
+    } else if !is_executable(&exe_path) {
+        checks.push(fail(
+            CheckKind::Executable,
+            format!("{} is not executable", exe_path.display()),
+        ));
+    } else {
+        checks.push(pass(
+            CheckKind::Executable,
+            format!("{} is executable", exe_path.display()),
+        ));
+    }
+
+    if same_file(&exe_path, &expected.path) {
+        checks.push(pass(
+            CheckKind::CurrentExecutable,
+            "Manifest points to this executable",
+        ));
+    } else {
+        checks.push(fail(
+            CheckKind::CurrentExecutable,
+            format!(
+                "Manifest points to {}, run `relocate` to point it to {}",
+                exe_path.display(),
+                expected.path.display()
+            ),
+        ));
+    }
+
+    let expected = expected.for_browser(browser);
+    let missing = expected
+        .allowed_origins
+        .iter()
+        .filter(|id| !manifest.allowed_origins.contains(id))
+        .chain(
+            expected
+                .allowed_extensions
+                .iter()
+                .filter(|id| !manifest.allowed_extensions.contains(id)),
+        )
+        .cloned()
+        .collect::<Vec<_>>();
+    if missing.is_empty() {
+        checks.push(pass(CheckKind::AllowedIds, "Extension is allowed"));
+    } else {
+        checks.push(fail(
+            CheckKind::AllowedIds,
+            format!("Not allowed: {}", missing.join(", ")),
+        ));
+    }
+
+    report(checks)
+}
+
+/// Human readable report
+pub fn format_reports(reports: &[BrowserReport]) -> String {
+    let mut output = String::new();
+    for report in reports {
+        match report.status {
+            Status::Installed => output.push_str(&format!("{:?}\n", report.browser)),
+            Status::NotInstalled => {
+                output.push_str(&format!("{:?}: not installed\n", report.browser))
+            }
+            Status::Unavailable => output.push_str(&format!(
+                "{:?}: not available on this platform\n",
+                report.browser
+            )),
+        }
+        for check in &report.checks {
+            let status = if check.ok { "ok" } else { "FAIL" };
+            output.push_str(&format!("  [{}] {}\n", status, check.message));
+        }
+    }
+    output
+}
+
+fn pass(kind: CheckKind, message: impl Into<String>) -> Check {
+    Check {
+        kind,
+        ok: true,
+        message: message.into(),
+    }
+}
+
+fn fail(kind: CheckKind, message: impl Into<String>) -> Check {
+    Check {
+        kind,
+        ok: false,
+        message: message.into(),
+    }
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    path.metadata()
+        .map(|metadata| metadata.permissions().mode() & 0o111 != 0)
+        .unwrap_or(false)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "exe")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expected(path: PathBuf) -> NativeManifestJson {
+        NativeManifestJson {
+            path,
+            name: "f_browser_helper_app".into(),
+            description: "Browser helper app".into(),
+            type_: "stdio".into(),
+            allowed_origins: vec!["chrome-extension://dnmkkgomoldfnbpjolhekmnoligmhdnc/".into()],
+            allowed_extensions: vec!["f_browser_helper_ext@oksidi.com".into()],
+        }
+    }
+
+    fn kinds(report: &BrowserReport, ok: bool) -> Vec<CheckKind> {
+        report
+            .checks
+            .iter()
+            .filter(|check| check.ok == ok)
+            .map(|check| check.kind)
+            .collect()
+    }
+
+    #[test]
+    fn test_not_installed() {
+        let report = diagnose_manifest(Browser::Chrome, &expected("/x".into()), None);
+        assert_eq!(report.status, Status::NotInstalled);
+        assert!(report.checks.is_empty());
+        assert!(!report.ok());
+        assert_eq!(
+            format_reports(std::slice::from_ref(&report)),
+            "Chrome: not installed\n"
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_unavailable() {
+        let reports = diagnose(&[Browser::Arc], &expected("/x".into()));
+        assert_eq!(reports[0].status, Status::Unavailable);
+        assert_eq!(
+            format_reports(&reports),
+            "Arc: not available on this platform\n"
+        );
+    }
+
+    #[test]
+    fn test_installation_ok() {
+        let dir = tempfile::tempdir().unwrap();
+        let manifest_path = dir.path().join("f_browser_helper_app.json");
+        let report = |browser, ok: bool| BrowserReport {
+            checks: vec![if ok {
+                pass(CheckKind::ValidJson, "")
+            } else {
+                fail(CheckKind::ValidJson, "")
+            }],
+            ..diagnose_manifest(browser, &expected("/x".into()), Some(&manifest_path))
+        };
+        let missing = diagnose_manifest(Browser::Edge, &expected("/x".into()), None);
+
+        // Missing browsers don't count, unless they are required
+        let reports = [report(Browser::Firefox, true), missing.clone()];
+        assert!(installation_ok(&reports, false));
+        assert!(!installation_ok(&reports, true));
+        assert!(!installation_ok(&[report(Browser::Chrome, false)], false));
+        assert!(!installation_ok(&[missing], false));
+    }
+
+    #[test]
+    fn test_invalid_json() {
+        let dir = tempfile::tempdir().unwrap();
+        let manifest_path = dir.path().join("f_browser_helper_app.json");
+        std::fs::write(&manifest_path, "{").unwrap();
+
+        let report = diagnose_manifest(
+            Browser::Chrome,
+            &expected("/x".into()),
+            Some(&manifest_path),
+        );
+        assert_eq!(kinds(&report, true), vec![CheckKind::Registered]);
+        assert_eq!(kinds(&report, false), vec![CheckKind::ValidJson]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_installed() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let exe_path = dir.path().join("fbrowserhelper");
+        std::fs::write(&exe_path, "").unwrap();
+        let expected = expected(exe_path.clone());
+        let manifest_path = dir.path().join("f_browser_helper_app.json");
+        std::fs::write(
+            &manifest_path,
+            serde_json::to_string(&expected.for_browser(Browser::Firefox)).unwrap(),
+        )
+        .unwrap();
+
+        // Not executable yet
+        let report = diagnose_manifest(Browser::Firefox, &expected, Some(&manifest_path));
+        assert_eq!(kinds(&report, false), vec![CheckKind::Executable]);
+
+        std::fs::set_permissions(&exe_path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let report = diagnose_manifest(Browser::Firefox, &expected, Some(&manifest_path));
+        assert!(
+            report.ok(),
+            "{}",
+            format_reports(std::slice::from_ref(&report))
+        );
+
+        // Another copy of the executable
+        let other = NativeManifestJson {
+            path: dir.path().join("other"),
+            ..expected.clone()
+        };
+        let report = diagnose_manifest(Browser::Firefox, &other, Some(&manifest_path));
+        assert_eq!(kinds(&report, false), vec![CheckKind::CurrentExecutable]);
+
+        // Firefox manifest has no origins for Chrome
+        let report = diagnose_manifest(Browser::Chrome, &expected, Some(&manifest_path));
+        assert_eq!(kinds(&report, false), vec![CheckKind::AllowedIds]);
+    }
+
+    #[test]
+    fn test_report_json() {
+        let report = diagnose_manifest(Browser::Edge, &expected("/x".into()), None);
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["browser"], "edge");
+        assert_eq!(json["status"], "notInstalled");
+        assert_eq!(json["manifestPath"], serde_json::Value::Null);
+        assert_eq!(json["checks"], serde_json::json!([]));
+    }
+}
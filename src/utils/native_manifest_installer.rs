@@ -1,72 +1,906 @@
-use serde::Serialize;
-use std::path::PathBuf;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
 
+/// Browsers sharing the hosts of another browser share the installation too,
+/// e.g. Brave and Vivaldi read Chrome's registry key on Windows
 #[derive(Debug, clap::ValueEnum, Clone, Copy, Serialize, PartialEq, Eq)]
 #[serde(rename_all = "kebab-case")]
 pub enum Browser {
     Chrome,
+    Chromium,
     Firefox,
     Edge,
+    Brave,
+    Vivaldi,
+    Opera,
+    Arc,
+    #[value(name = "librewolf")]
+    #[serde(rename = "librewolf")]
+    LibreWolf,
+    Waterfox,
+}
+
+impl Browser {
+    /// Firefox and its forks identify extensions by ID instead of origin
+    pub fn is_firefox_based(self) -> bool {
+        matches!(
+            self,
+            Browser::Firefox | Browser::LibreWolf | Browser::Waterfox
+        )
+    }
+}
+
+/// Install for the current user or for all users of the machine
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InstallScope {
+    User,
+    System,
 }
 
 // https://developer.chrome.com/docs/extensions/develop/concepts/native-messaging#native-messaging-host
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct NativeManifestJson {
     pub name: String,
     pub description: String,
     pub path: PathBuf,
     #[serde(rename = "type")]
     pub type_: String,
-    #[serde(skip_serializing_if = "Vec::is_empty")]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub allowed_origins: Vec<String>,
-    #[serde(skip_serializing_if = "Vec::is_empty")]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub allowed_extensions: Vec<String>,
 }
 
-pub fn install(browser: Browser, extension: &NativeManifestJson) -> Result<(), &'static str> {
-    let manifest_json_path = extension
-        .path
-        .with_file_name(format!("native_manifest_{:?}.json", browser));
-
-    // TODO: executable path could be relative in the manifest.json
-
-    // Write the manifest.json
-    std::fs::write(
-        &manifest_json_path,
-        serde_json::to_string_pretty(&NativeManifestJson {
-            name: extension.name.clone(),
-            description: extension.description.clone(),
-            path: extension.path.clone(),
-            type_: extension.type_.clone(),
-            allowed_origins: if browser != Browser::Firefox {
-                extension.allowed_origins.clone()
+impl NativeManifestJson {
+    /// Manifest for the browser, Firefox-based browsers use extension IDs and
+    /// the others use origins
+    pub fn for_browser(&self, browser: Browser) -> NativeManifestJson {
+        NativeManifestJson {
+            allowed_origins: if !browser.is_firefox_based() {
+                self.allowed_origins.clone()
             } else {
                 vec![]
             },
-            allowed_extensions: if browser == Browser::Firefox {
-                extension.allowed_extensions.clone()
+            allowed_extensions: if browser.is_firefox_based() {
+                self.allowed_extensions.clone()
             } else {
                 vec![]
             },
-        })
-        .map_err(|_| "Failed to serialize JSON")?,
+            ..self.clone()
+        }
+    }
+}
+
+/// Check the name of the host, e.g. `com.example.host`
+///
+/// Browsers allow lowercase letters, digits, underscores and dots, but not
+/// dots at the start or end or next to each other.
+pub fn parse_host_name(name: &str) -> Result<String, &'static str> {
+    let valid_chars = name
+        .chars()
+        .all(|c| matches!(c, 'a'..='z' | '0'..='9' | '_' | '.'));
+    if name.is_empty() || !valid_chars || name.split('.').any(str::is_empty) {
+        return Err("Host name can have lowercase letters, digits, underscores and dots");
+    }
+    Ok(name.to_string())
+}
+
+/// Check an allowed origin of Chromium-based browsers, `chrome-extension://<id>/`
+///
+/// Extension IDs are 32 letters from `a` to `p`.
+pub fn parse_allowed_origin(origin: &str) -> Result<String, &'static str> {
+    let id = origin
+        .strip_prefix("chrome-extension://")
+        .and_then(|rest| rest.strip_suffix('/'))
+        .ok_or("Allowed origin must be chrome-extension://<id>/")?;
+    if id.len() != 32 || !id.chars().all(|c| matches!(c, 'a'..='p')) {
+        return Err("Extension ID must be 32 letters from a to p");
+    }
+    Ok(origin.to_string())
+}
+
+/// Check an allowed extension of Firefox-based browsers, `name@domain` or
+/// `{uuid}`
+pub fn parse_allowed_extension(id: &str) -> Result<String, &'static str> {
+    let is_email = id
+        .split_once('@')
+        .is_some_and(|(name, domain)| !name.is_empty() && !domain.is_empty());
+    let is_uuid = id.starts_with('{')
+        && id.ends_with('}')
+        && id.len() == 38
+        && id[1..37].chars().all(|c| c.is_ascii_hexdigit() || c == '-');
+    if (!is_email && !is_uuid) || id.chars().any(char::is_whitespace) {
+        return Err("Allowed extension must be name@domain or {uuid}");
+    }
+    Ok(id.to_string())
+}
+
+/// Whether browsers accept a `path` relative to the manifest
+///
+/// Only Windows allows it, and there the manifest is next to the executable.
+pub const RELATIVE_PATH_SUPPORTED: bool = cfg!(windows);
+
+/// Install the host for the browser, `extension.path` is the executable
+///
+/// In portable mode the manifest points to the executable with a relative
+/// path where that's supported, so the folder can be moved as a whole.
+pub fn install(
+    browser: Browser,
+    extension: &NativeManifestJson,
+    scope: InstallScope,
+    portable: bool,
+) -> Result<(), &'static str> {
+    let mut manifest = extension.for_browser(browser);
+    if portable && RELATIVE_PATH_SUPPORTED {
+        manifest.path = extension
+            .path
+            .file_name()
+            .ok_or("Invalid executable path")?
+            .into();
+    }
+
+    let manifest_json =
+        serde_json::to_string_pretty(&manifest).map_err(|_| "Failed to serialize JSON")?;
+
+    register(
+        location_owner(browser, cfg!(windows)),
+        extension,
+        &manifest_json,
+        scope,
     )
-    .map_err(|_| "Failed to write manifest.json")?;
-
-    // Create the registry key, point it to the manifest.json file
-    winreg::RegKey::predef(winreg::enums::HKEY_CURRENT_USER)
-        .create_subkey(
-            PathBuf::from(match browser {
-                Browser::Chrome => r"Software\Google\Chrome\NativeMessagingHosts",
-                Browser::Firefox => r"Software\Mozilla\NativeMessagingHosts",
-                Browser::Edge => r"Software\Microsoft\Edge\NativeMessagingHosts",
-            })
-            .join(&extension.name),
-        )
-        .map_err(|_| "Failed to create registry key")?
-        .0
-        .set_value("", &manifest_json_path.as_os_str())
-        .map_err(|_| "Failed to set registry key value")?;
+}
+
+/// Installation whose manifest points to another executable
+#[derive(Debug, Clone)]
+pub struct MovedInstall {
+    pub browser: Browser,
+    pub scope: InstallScope,
+    pub manifest_path: PathBuf,
+    /// Installed manifest, `None` if it moved away with the executable
+    pub manifest: Option<NativeManifestJson>,
+    /// Executable the manifest points to
+    pub exe_path: PathBuf,
+}
+
+/// Find the installations which don't point to `current_exe`, e.g. after the
+/// executable was moved to another folder
+pub fn find_moved_installs(
+    browsers: &[Browser],
+    name: &str,
+    current_exe: &Path,
+) -> Vec<MovedInstall> {
+    let mut moved = vec![];
+    for &browser in browsers {
+        for scope in [InstallScope::User, InstallScope::System] {
+            let Ok(Some(manifest_path)) = installed_manifest_path(browser, name, scope) else {
+                continue;
+            };
+            // Browsers sharing the hosts of another browser
+            if moved
+                .iter()
+                .any(|moved: &MovedInstall| moved.manifest_path == manifest_path)
+            {
+                continue;
+            }
+            let manifest = read_manifest(&manifest_path);
+            let exe_path = match &manifest {
+                Some(manifest) => resolve_exe_path(&manifest_path, manifest),
+                // Manifest is next to the executable on Windows
+                None => manifest_path.clone(),
+            };
+            if !same_file(&exe_path, current_exe) {
+                moved.push(MovedInstall {
+                    browser,
+                    scope,
+                    manifest_path,
+                    manifest,
+                    exe_path,
+                });
+            }
+        }
+    }
+    moved
+}
+
+/// Point the moved installation to `current_exe`
+///
+/// The installed manifest is kept as is, if it's gone `fallback` is used.
+/// Relative paths stay relative.
+pub fn relocate(
+    moved: &MovedInstall,
+    fallback: &NativeManifestJson,
+    current_exe: &Path,
+) -> Result<(), &'static str> {
+    let manifest = moved.manifest.as_ref().unwrap_or(fallback);
+    let portable = moved
+        .manifest
+        .as_ref()
+        .is_some_and(|manifest| manifest.path.is_relative());
+    let extension = NativeManifestJson {
+        path: current_exe.to_path_buf(),
+        ..manifest.clone()
+    };
+    install(moved.browser, &extension, moved.scope, portable)
+}
+
+/// Executable of the manifest, relative paths are relative to the manifest
+pub fn resolve_exe_path(manifest_path: &Path, manifest: &NativeManifestJson) -> PathBuf {
+    match manifest_path.parent() {
+        Some(dir) => dir.join(&manifest.path),
+        None => manifest.path.clone(),
+    }
+}
+
+pub fn read_manifest(manifest_path: &Path) -> Option<NativeManifestJson> {
+    serde_json::from_slice(&std::fs::read(manifest_path).ok()?).ok()
+}
+
+pub(crate) fn same_file(a: &Path, b: &Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+/// Remove what `install` created for the browser
+///
+/// Returns the removed files and registry keys, nothing is removed when the
+/// host isn't installed. Browsers reading another browser's hosts are
+/// skipped, removing the host would remove it from that browser too.
+pub fn uninstall(
+    browser: Browser,
+    extension: &NativeManifestJson,
+    scope: InstallScope,
+) -> Result<Vec<String>, &'static str> {
+    if shared_location(browser).is_some() {
+        return Ok(vec![]);
+    }
+    unregister(browser, extension, scope)
+}
+
+/// Browser whose hosts `browser` reads, the browser itself unless it reads
+/// another browser's
+///
+/// Opera reads Chrome's hosts everywhere, the other Chromium forks except
+/// Edge only on Windows.
+fn location_owner(browser: Browser, windows: bool) -> Browser {
+    match browser {
+        Browser::Opera => Browser::Chrome,
+        Browser::Brave | Browser::Vivaldi | Browser::Arc if windows => Browser::Chrome,
+        browser => browser,
+    }
+}
+
+/// Browser whose hosts `browser` reads on this platform, e.g. Chrome for
+/// Opera, `None` if the browser has its own location
+pub fn shared_location(browser: Browser) -> Option<Browser> {
+    let owner = location_owner(browser, cfg!(windows));
+    (owner != browser).then_some(owner)
+}
+
+/// Browsers reading the hosts of `browser` on this platform
+pub fn browsers_sharing_location(browser: Browser) -> Vec<Browser> {
+    Browser::value_variants()
+        .iter()
+        .copied()
+        .filter(|&other| shared_location(other) == Some(browser))
+        .collect()
+}
+
+/// Whether the browser exists on this platform, e.g. Arc doesn't on Linux
+#[cfg(target_os = "linux")]
+pub fn is_available(browser: Browser) -> bool {
+    linux_manifest_dir(browser, InstallScope::User, Path::new("")).is_some()
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn is_available(_browser: Browser) -> bool {
+    true
+}
+
+/// Directory where the browser looks for native messaging hosts on Linux
+///
+/// Arc isn't available on Linux.
+///
+/// <https://developer.chrome.com/docs/extensions/develop/concepts/native-messaging#native-messaging-host-location>
+/// <https://developer.mozilla.org/en-US/docs/Mozilla/Add-ons/WebExtensions/Native_manifests#linux>
+pub fn linux_manifest_dir(browser: Browser, scope: InstallScope, home: &Path) -> Option<PathBuf> {
+    Some(match scope {
+        InstallScope::User => home.join(match browser {
+            // Opera reads Chrome's hosts
+            Browser::Chrome | Browser::Opera => ".config/google-chrome/NativeMessagingHosts",
+            Browser::Chromium => ".config/chromium/NativeMessagingHosts",
+            Browser::Firefox => ".mozilla/native-messaging-hosts",
+            Browser::Edge => ".config/microsoft-edge/NativeMessagingHosts",
+            Browser::Brave => ".config/BraveSoftware/Brave-Browser/NativeMessagingHosts",
+            Browser::Vivaldi => ".config/vivaldi/NativeMessagingHosts",
+            Browser::Arc => return None,
+            Browser::LibreWolf => ".librewolf/native-messaging-hosts",
+            Browser::Waterfox => ".waterfox/native-messaging-hosts",
+        }),
+        InstallScope::System => PathBuf::from(match browser {
+            Browser::Chrome | Browser::Opera => "/etc/opt/chrome/native-messaging-hosts",
+            Browser::Chromium => "/etc/chromium/native-messaging-hosts",
+            Browser::Firefox => "/usr/lib/mozilla/native-messaging-hosts",
+            Browser::Edge => "/etc/opt/edge/native-messaging-hosts",
+            Browser::Brave => "/etc/opt/brave.com/brave/native-messaging-hosts",
+            Browser::Vivaldi => "/etc/vivaldi/native-messaging-hosts",
+            Browser::Arc => return None,
+            Browser::LibreWolf => "/usr/lib/librewolf/native-messaging-hosts",
+            Browser::Waterfox => "/usr/lib/waterfox/native-messaging-hosts",
+        }),
+    })
+}
+
+/// Directory where the browser looks for native messaging hosts on macOS
+///
+/// <https://developer.chrome.com/docs/extensions/develop/concepts/native-messaging#native-messaging-host-location>
+/// <https://developer.mozilla.org/en-US/docs/Mozilla/Add-ons/WebExtensions/Native_manifests#macos>
+pub fn macos_manifest_dir(browser: Browser, scope: InstallScope, home: &Path) -> PathBuf {
+    match scope {
+        InstallScope::User => home
+            .join("Library/Application Support")
+            .join(match browser {
+                Browser::Chrome | Browser::Opera => "Google/Chrome/NativeMessagingHosts",
+                Browser::Chromium => "Chromium/NativeMessagingHosts",
+                Browser::Firefox => "Mozilla/NativeMessagingHosts",
+                Browser::Edge => "Microsoft Edge/NativeMessagingHosts",
+                Browser::Brave => "BraveSoftware/Brave-Browser/NativeMessagingHosts",
+                Browser::Vivaldi => "Vivaldi/NativeMessagingHosts",
+                Browser::Arc => "Arc/User Data/NativeMessagingHosts",
+                Browser::LibreWolf => "LibreWolf/NativeMessagingHosts",
+                Browser::Waterfox => "Waterfox/NativeMessagingHosts",
+            }),
+        InstallScope::System => PathBuf::from(match browser {
+            Browser::Chrome | Browser::Opera => "/Library/Google/Chrome/NativeMessagingHosts",
+            Browser::Chromium => "/Library/Application Support/Chromium/NativeMessagingHosts",
+            Browser::Firefox => "/Library/Application Support/Mozilla/NativeMessagingHosts",
+            Browser::Edge => "/Library/Microsoft/Edge/NativeMessagingHosts",
+            Browser::Brave => {
+                "/Library/Application Support/BraveSoftware/Brave-Browser/NativeMessagingHosts"
+            }
+            Browser::Vivaldi => "/Library/Application Support/Vivaldi/NativeMessagingHosts",
+            Browser::Arc => "/Library/Application Support/Arc/User Data/NativeMessagingHosts",
+            Browser::LibreWolf => "/Library/Application Support/LibreWolf/NativeMessagingHosts",
+            Browser::Waterfox => "/Library/Application Support/Waterfox/NativeMessagingHosts",
+        }),
+    }
+}
+
+/// Registry key of native messaging hosts on Windows, under HKCU or HKLM
+///
+/// Chromium forks other than Edge read Chrome's key, see `location_owner`.
+pub fn windows_registry_key(browser: Browser) -> &'static str {
+    match browser {
+        Browser::Chrome | Browser::Brave | Browser::Vivaldi | Browser::Opera | Browser::Arc => {
+            r"Software\Google\Chrome\NativeMessagingHosts"
+        }
+        Browser::Chromium => r"Software\Chromium\NativeMessagingHosts",
+        Browser::Firefox => r"Software\Mozilla\NativeMessagingHosts",
+        Browser::Edge => r"Software\Microsoft\Edge\NativeMessagingHosts",
+        Browser::LibreWolf => r"Software\LibreWolf\NativeMessagingHosts",
+        Browser::Waterfox => r"Software\Waterfox\NativeMessagingHosts",
+    }
+}
+
+/// Manifest the browser would find for the host, if it's installed
+#[cfg(windows)]
+pub fn installed_manifest_path(
+    browser: Browser,
+    name: &str,
+    scope: InstallScope,
+) -> Result<Option<PathBuf>, &'static str> {
+    let root = match scope {
+        InstallScope::User => winreg::enums::HKEY_CURRENT_USER,
+        InstallScope::System => winreg::enums::HKEY_LOCAL_MACHINE,
+    };
+    let Ok(key) = winreg::RegKey::predef(root)
+        .open_subkey(PathBuf::from(windows_registry_key(browser)).join(name))
+    else {
+        return Ok(None);
+    };
+    let path: String = key
+        .get_value("")
+        .map_err(|_| "Failed to read registry key value")?;
+    Ok(Some(PathBuf::from(path)))
+}
+
+/// Manifest the browser would find for the host, if it's installed
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+pub fn installed_manifest_path(
+    browser: Browser,
+    name: &str,
+    scope: InstallScope,
+) -> Result<Option<PathBuf>, &'static str> {
+    let path = manifest_dir(browser, scope)?.join(format!("{}.json", name));
+    Ok(path.is_file().then_some(path))
+}
+
+#[cfg(not(any(windows, target_os = "linux", target_os = "macos")))]
+pub fn installed_manifest_path(
+    _browser: Browser,
+    _name: &str,
+    _scope: InstallScope,
+) -> Result<Option<PathBuf>, &'static str> {
+    Err("Registering native messaging hosts is not supported on this platform")
+}
+
+/// Directory of native messaging hosts on this platform
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn manifest_dir(browser: Browser, scope: InstallScope) -> Result<PathBuf, &'static str> {
+    let home = dirs::home_dir().ok_or("Failed to get home directory")?;
+    #[cfg(target_os = "linux")]
+    return linux_manifest_dir(browser, scope, &home)
+        .ok_or("Browser is not available on this platform");
+    #[cfg(target_os = "macos")]
+    return Ok(macos_manifest_dir(browser, scope, &home));
+}
+
+/// Write the manifest.json next to the executable, create the registry key
+/// and point it to the file
+#[cfg(windows)]
+fn register(
+    browser: Browser,
+    extension: &NativeManifestJson,
+    manifest_json: &str,
+    scope: InstallScope,
+) -> Result<(), &'static str> {
+    let manifest_json_path = extension
+        .path
+        .with_file_name(format!("native_manifest_{:?}.json", browser));
+    std::fs::write(&manifest_json_path, manifest_json)
+        .map_err(|_| "Failed to write manifest.json")?;
+
+    winreg::RegKey::predef(match scope {
+        InstallScope::User => winreg::enums::HKEY_CURRENT_USER,
+        InstallScope::System => winreg::enums::HKEY_LOCAL_MACHINE,
+    })
+    .create_subkey(PathBuf::from(windows_registry_key(browser)).join(&extension.name))
+    .map_err(|_| "Failed to create registry key")?
+    .0
+    .set_value("", &manifest_json_path.as_os_str())
+    .map_err(|_| "Failed to set registry key value")?;
 
     Ok(())
 }
+
+/// Write the manifest.json to the directory the browser reads
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn register(
+    browser: Browser,
+    extension: &NativeManifestJson,
+    manifest_json: &str,
+    scope: InstallScope,
+) -> Result<(), &'static str> {
+    let dir = manifest_dir(browser, scope)?;
+    write_manifest(&dir, &extension.name, manifest_json)?;
+    Ok(())
+}
+
+/// Remove the registry key and the manifest.json next to the executable
+#[cfg(windows)]
+fn unregister(
+    browser: Browser,
+    extension: &NativeManifestJson,
+    scope: InstallScope,
+) -> Result<Vec<String>, &'static str> {
+    let mut removed = vec![];
+
+    let (root, root_name) = match scope {
+        InstallScope::User => (winreg::enums::HKEY_CURRENT_USER, "HKEY_CURRENT_USER"),
+        InstallScope::System => (winreg::enums::HKEY_LOCAL_MACHINE, "HKEY_LOCAL_MACHINE"),
+    };
+    let key = PathBuf::from(windows_registry_key(browser)).join(&extension.name);
+    match winreg::RegKey::predef(root).delete_subkey_all(&key) {
+        Ok(()) => removed.push(format!(r"{}\{}", root_name, key.display())),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+        Err(_) => return Err("Failed to delete registry key"),
+    }
+
+    let manifest_json_path = extension
+        .path
+        .with_file_name(format!("native_manifest_{:?}.json", browser));
+    if remove_file(&manifest_json_path)? {
+        removed.push(manifest_json_path.display().to_string());
+    }
+
+    Ok(removed)
+}
+
+/// Remove the manifest.json from the directory the browser reads
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn unregister(
+    browser: Browser,
+    extension: &NativeManifestJson,
+    scope: InstallScope,
+) -> Result<Vec<String>, &'static str> {
+    let dir = manifest_dir(browser, scope)?;
+    Ok(remove_manifest(&dir, &extension.name)?
+        .map(|path| path.display().to_string())
+        .into_iter()
+        .collect())
+}
+
+#[cfg(not(any(windows, target_os = "linux", target_os = "macos")))]
+fn unregister(
+    _browser: Browser,
+    _extension: &NativeManifestJson,
+    _scope: InstallScope,
+) -> Result<Vec<String>, &'static str> {
+    Err("Registering native messaging hosts is not supported on this platform")
+}
+
+#[cfg(not(any(windows, target_os = "linux", target_os = "macos")))]
+fn register(
+    _browser: Browser,
+    _extension: &NativeManifestJson,
+    _manifest_json: &str,
+    _scope: InstallScope,
+) -> Result<(), &'static str> {
+    Err("Registering native messaging hosts is not supported on this platform")
+}
+
+/// Write `<name>.json` to the directory of native messaging hosts
+#[cfg(not(windows))]
+fn write_manifest(dir: &Path, name: &str, manifest_json: &str) -> Result<PathBuf, &'static str> {
+    std::fs::create_dir_all(dir).map_err(|_| "Failed to create manifest directory")?;
+    let path = dir.join(format!("{}.json", name));
+    std::fs::write(&path, manifest_json).map_err(|_| "Failed to write manifest.json")?;
+    Ok(path)
+}
+
+/// Remove `<name>.json` from the directory of native messaging hosts
+#[cfg(not(windows))]
+fn remove_manifest(dir: &Path, name: &str) -> Result<Option<PathBuf>, &'static str> {
+    let path = dir.join(format!("{}.json", name));
+    Ok(remove_file(&path)?.then_some(path))
+}
+
+/// Remove the file, `false` if it didn't exist
+fn remove_file(path: &Path) -> Result<bool, &'static str> {
+    match std::fs::remove_file(path) {
+        Ok(()) => Ok(true),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(_) => Err("Failed to remove manifest.json"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::ValueEnum;
+
+    use super::*;
+
+    fn extension() -> NativeManifestJson {
+        NativeManifestJson {
+            path: "/opt/fbrowserhelper/fbrowserhelper".into(),
+            name: "f_browser_helper_app".into(),
+            description: "Browser helper app".into(),
+            type_: "stdio".into(),
+            allowed_origins: vec!["chrome-extension://dnmkkgomoldfnbpjolhekmnoligmhdnc/".into()],
+            allowed_extensions: vec!["f_browser_helper_ext@oksidi.com".into()],
+        }
+    }
+
+    #[test]
+    fn test_parse_host_name() {
+        assert!(parse_host_name("f_browser_helper_app").is_ok());
+        assert!(parse_host_name("com.example.host_2").is_ok());
+        assert!(parse_host_name("").is_err());
+        assert!(parse_host_name("Com.Example").is_err());
+        assert!(parse_host_name("com..example").is_err());
+        assert!(parse_host_name(".com").is_err());
+        assert!(parse_host_name("com-example").is_err());
+    }
+
+    #[test]
+    fn test_parse_allowed_origin() {
+        assert!(
+            parse_allowed_origin("chrome-extension://dnmkkgomoldfnbpjolhekmnoligmhdnc/").is_ok()
+        );
+        assert!(
+            parse_allowed_origin("chrome-extension://dnmkkgomoldfnbpjolhekmnoligmhdnc").is_err()
+        );
+        assert!(parse_allowed_origin("dnmkkgomoldfnbpjolhekmnoligmhdnc").is_err());
+        assert!(
+            parse_allowed_origin("chrome-extension://dnmkkgomoldfnbpjolhekmnoligmhdnz/").is_err()
+        );
+        assert!(parse_allowed_origin("chrome-extension://abc/").is_err());
+        assert!(parse_allowed_origin("https://example.com/").is_err());
+    }
+
+    #[test]
+    fn test_parse_allowed_extension() {
+        assert!(parse_allowed_extension("f_browser_helper_ext@oksidi.com").is_ok());
+        assert!(parse_allowed_extension("{d2a4b6c8-1234-4abc-9def-0123456789ab}").is_ok());
+        assert!(parse_allowed_extension("@oksidi.com").is_err());
+        assert!(parse_allowed_extension("name@").is_err());
+        assert!(parse_allowed_extension("{not-a-uuid}").is_err());
+        assert!(
+            parse_allowed_extension("chrome-extension://dnmkkgomoldfnbpjolhekmnoligmhdnc/")
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_resolve_exe_path() {
+        let mut manifest = extension();
+        let manifest_path = Path::new("/opt/fbrowserhelper/native_manifest_Chrome.json");
+        assert_eq!(
+            resolve_exe_path(manifest_path, &manifest),
+            Path::new("/opt/fbrowserhelper/fbrowserhelper")
+        );
+
+        manifest.path = "fbrowserhelper".into();
+        let manifest_path = Path::new("/media/usb/fbrowserhelper/native_manifest_Chrome.json");
+        assert_eq!(
+            resolve_exe_path(manifest_path, &manifest),
+            Path::new("/media/usb/fbrowserhelper/fbrowserhelper")
+        );
+    }
+
+    #[test]
+    fn test_read_manifest() {
+        let dir = tempfile::tempdir().unwrap();
+        let manifest_path = dir.path().join("f_browser_helper_app.json");
+        assert!(read_manifest(&manifest_path).is_none());
+
+        std::fs::write(
+            &manifest_path,
+            serde_json::to_string(&extension().for_browser(Browser::Chrome)).unwrap(),
+        )
+        .unwrap();
+        let manifest = read_manifest(&manifest_path).unwrap();
+        assert_eq!(
+            manifest.path,
+            Path::new("/opt/fbrowserhelper/fbrowserhelper")
+        );
+        assert!(manifest.allowed_extensions.is_empty());
+    }
+
+    #[test]
+    fn test_manifest_for_browser() {
+        let chrome = serde_json::to_value(extension().for_browser(Browser::Chrome)).unwrap();
+        assert!(chrome.get("allowed_origins").is_some());
+        assert!(chrome.get("allowed_extensions").is_none());
+
+        let firefox = serde_json::to_value(extension().for_browser(Browser::Firefox)).unwrap();
+        assert!(firefox.get("allowed_origins").is_none());
+        assert!(firefox.get("allowed_extensions").is_some());
+    }
+
+    #[test]
+    fn test_linux_manifest_dir() {
+        let home = Path::new("/home/user");
+        assert_eq!(
+            linux_manifest_dir(Browser::Firefox, InstallScope::User, home).unwrap(),
+            Path::new("/home/user/.mozilla/native-messaging-hosts")
+        );
+        assert_eq!(
+            linux_manifest_dir(Browser::Chromium, InstallScope::User, home).unwrap(),
+            Path::new("/home/user/.config/chromium/NativeMessagingHosts")
+        );
+        assert_eq!(
+            linux_manifest_dir(Browser::Chrome, InstallScope::System, home).unwrap(),
+            Path::new("/etc/opt/chrome/native-messaging-hosts")
+        );
+        assert_eq!(
+            linux_manifest_dir(Browser::Edge, InstallScope::System, home).unwrap(),
+            Path::new("/etc/opt/edge/native-messaging-hosts")
+        );
+        assert_eq!(
+            linux_manifest_dir(Browser::Brave, InstallScope::User, home).unwrap(),
+            Path::new("/home/user/.config/BraveSoftware/Brave-Browser/NativeMessagingHosts")
+        );
+        assert_eq!(
+            linux_manifest_dir(Browser::LibreWolf, InstallScope::User, home).unwrap(),
+            Path::new("/home/user/.librewolf/native-messaging-hosts")
+        );
+        assert_eq!(
+            linux_manifest_dir(Browser::Arc, InstallScope::User, home),
+            None
+        );
+        assert_eq!(
+            linux_manifest_dir(Browser::Arc, InstallScope::System, home),
+            None
+        );
+    }
+
+    #[test]
+    fn test_manifest_dirs_are_per_browser() {
+        // Browsers reading another browser's hosts
+        let shared = |browser| location_owner(browser, false);
+
+        let home = Path::new("/home/user");
+        for scope in [InstallScope::User, InstallScope::System] {
+            for a in Browser::value_variants() {
+                for b in Browser::value_variants() {
+                    if shared(*a) == shared(*b) {
+                        continue;
+                    }
+                    if let (Some(dir_a), Some(dir_b)) = (
+                        linux_manifest_dir(*a, scope, home),
+                        linux_manifest_dir(*b, scope, home),
+                    ) {
+                        assert_ne!(dir_a, dir_b, "{:?} and {:?} on Linux", a, b);
+                    }
+                    assert_ne!(
+                        macos_manifest_dir(*a, scope, home),
+                        macos_manifest_dir(*b, scope, home),
+                        "{:?} and {:?} on macOS",
+                        a,
+                        b
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_windows_registry_key() {
+        assert_eq!(
+            windows_registry_key(Browser::Firefox),
+            r"Software\Mozilla\NativeMessagingHosts"
+        );
+        assert_eq!(
+            windows_registry_key(Browser::Edge),
+            r"Software\Microsoft\Edge\NativeMessagingHosts"
+        );
+        assert_eq!(
+            windows_registry_key(Browser::Waterfox),
+            r"Software\Waterfox\NativeMessagingHosts"
+        );
+        for a in Browser::value_variants() {
+            for b in Browser::value_variants() {
+                assert_eq!(
+                    windows_registry_key(*a) == windows_registry_key(*b),
+                    location_owner(*a, true) == location_owner(*b, true),
+                    "{:?} and {:?}",
+                    a,
+                    b
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_firefox_based() {
+        for browser in Browser::value_variants() {
+            let manifest = extension().for_browser(*browser);
+            assert_eq!(
+                manifest.allowed_extensions.is_empty(),
+                !browser.is_firefox_based()
+            );
+            assert_eq!(
+                manifest.allowed_origins.is_empty(),
+                browser.is_firefox_based()
+            );
+        }
+        assert!(Browser::LibreWolf.is_firefox_based());
+        assert!(!Browser::Brave.is_firefox_based());
+    }
+
+    #[test]
+    fn test_macos_manifest_dir() {
+        let home = Path::new("/Users/user");
+        assert_eq!(
+            macos_manifest_dir(Browser::Firefox, InstallScope::User, home),
+            Path::new("/Users/user/Library/Application Support/Mozilla/NativeMessagingHosts")
+        );
+        assert_eq!(
+            macos_manifest_dir(Browser::Chrome, InstallScope::User, home),
+            Path::new("/Users/user/Library/Application Support/Google/Chrome/NativeMessagingHosts")
+        );
+        assert_eq!(
+            macos_manifest_dir(Browser::Edge, InstallScope::User, home),
+            Path::new(
+                "/Users/user/Library/Application Support/Microsoft Edge/NativeMessagingHosts"
+            )
+        );
+        assert_eq!(
+            macos_manifest_dir(Browser::Chrome, InstallScope::System, home),
+            Path::new("/Library/Google/Chrome/NativeMessagingHosts")
+        );
+        assert_eq!(
+            macos_manifest_dir(Browser::Firefox, InstallScope::System, home),
+            Path::new("/Library/Application Support/Mozilla/NativeMessagingHosts")
+        );
+        assert_eq!(
+            macos_manifest_dir(Browser::Arc, InstallScope::User, home),
+            Path::new("/Users/user/Library/Application Support/Arc/User Data/NativeMessagingHosts")
+        );
+        assert_eq!(
+            macos_manifest_dir(Browser::Waterfox, InstallScope::User, home),
+            Path::new("/Users/user/Library/Application Support/Waterfox/NativeMessagingHosts")
+        );
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn test_write_manifest_to_home() {
+        let home = tempfile::tempdir().unwrap();
+        let extension = extension();
+        for &browser in Browser::value_variants() {
+            let Some(dir) = linux_manifest_dir(browser, InstallScope::User, home.path()) else {
+                continue;
+            };
+            let json = serde_json::to_string_pretty(&extension.for_browser(browser)).unwrap();
+            let path = write_manifest(&dir, &extension.name, &json).unwrap();
+            assert_eq!(path, dir.join("f_browser_helper_app.json"));
+
+            let written: serde_json::Value =
+                serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+            assert_eq!(written["name"], "f_browser_helper_app");
+            assert_eq!(written["path"], "/opt/fbrowserhelper/fbrowserhelper");
+        }
+        assert!(home
+            .path()
+            .join(".config/google-chrome/NativeMessagingHosts/f_browser_helper_app.json")
+            .is_file());
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn test_uninstall_keeps_shared_location() {
+        assert_eq!(shared_location(Browser::Opera), Some(Browser::Chrome));
+        assert_eq!(shared_location(Browser::Chrome), None);
+        assert_eq!(browsers_sharing_location(Browser::Chrome), [Browser::Opera]);
+
+        // Opera is skipped before anything is touched
+        assert_eq!(
+            uninstall(Browser::Opera, &extension(), InstallScope::User),
+            Ok(vec![])
+        );
+
+        // Removing the location of every browser not skipped except Chrome
+        // leaves Chrome's host, which Opera reads too
+        let home = tempfile::tempdir().unwrap();
+        let chrome_dir =
+            linux_manifest_dir(Browser::Chrome, InstallScope::User, home.path()).unwrap();
+        let chrome_manifest = write_manifest(&chrome_dir, "f_browser_helper_app", "{}").unwrap();
+        for &browser in Browser::value_variants() {
+            if browser == Browser::Chrome || shared_location(browser).is_some() {
+                continue;
+            }
+            if let Some(dir) = linux_manifest_dir(browser, InstallScope::User, home.path()) {
+                assert_eq!(remove_manifest(&dir, "f_browser_helper_app"), Ok(None));
+            }
+        }
+        assert!(chrome_manifest.is_file());
+    }
+
+    #[test]
+    fn test_shared_locations_on_windows() {
+        for browser in [
+            Browser::Brave,
+            Browser::Vivaldi,
+            Browser::Opera,
+            Browser::Arc,
+        ] {
+            assert_eq!(location_owner(browser, true), Browser::Chrome);
+        }
+        assert_eq!(location_owner(Browser::Edge, true), Browser::Edge);
+        assert_eq!(location_owner(Browser::Brave, false), Browser::Brave);
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn test_remove_manifest() {
+        let home = tempfile::tempdir().unwrap();
+        let dir = linux_manifest_dir(Browser::Firefox, InstallScope::User, home.path()).unwrap();
+        assert_eq!(remove_manifest(&dir, "f_browser_helper_app"), Ok(None));
+
+        let path = write_manifest(&dir, "f_browser_helper_app", "{}").unwrap();
+        let other = write_manifest(&dir, "other_app", "{}").unwrap();
+        assert_eq!(
+            remove_manifest(&dir, "f_browser_helper_app"),
+            Ok(Some(path.clone()))
+        );
+        assert!(!path.exists());
+        assert!(other.exists());
+
+        // Nothing left to remove
+        assert_eq!(remove_manifest(&dir, "f_browser_helper_app"), Ok(None));
+    }
+}
@@ -2,73 +2,632 @@
 // https://github.com/neon64/chrome-native-messaging/blob/master/src/lib.rs
 // (MIT Licensed)
 
-// TODO: This could remove the need for the `events` module with traits
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
+use std::ops::ControlFlow;
 
-use serde::Serialize;
-use std::fmt::Debug;
+/// Message with an optional `id`
+///
+/// The `id` of a request is echoed back verbatim in its response or error, so
+/// the browser can match them. It can be any JSON value.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Envelope<T> {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<serde_json::Value>,
+    #[serde(flatten)]
+    pub message: T,
+}
 
-use crate::events::{MessageFromBrowser, MessageToError};
-use std::io::{Read, Write};
+impl<T> Envelope<T> {
+    pub fn new(id: Option<serde_json::Value>, message: T) -> Self {
+        Envelope { id, message }
+    }
+}
+
+/// Largest message the browser accepts from the host
+pub const MAX_MESSAGE_TO_BROWSER: u32 = 1024 * 1024;
 
-use crate::log;
+/// Default limit for messages from the browser
+///
+/// Browsers send up to 4 GiB, but our messages are small, icons included.
+pub const DEFAULT_MAX_MESSAGE_FROM_BROWSER: u32 = 16 * 1024 * 1024;
+
+/// Byte order of the length prefix
+///
+/// Browsers use the native order, the others are for transports between
+/// machines.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ByteOrder {
+    /// Order of the machine, which is what browsers use
+    #[default]
+    Native,
+    Little,
+    Big,
+}
+
+#[derive(Debug)]
+pub enum FrameError {
+    /// Input ended between frames, i.e. the other end closed the stream
+    Closed,
+    Io(std::io::Error),
+    TooLarge {
+        length: u32,
+        max_length: u32,
+    },
+}
+
+// Allow io::Error to be converted to FrameError
+impl From<std::io::Error> for FrameError {
+    fn from(err: std::io::Error) -> Self {
+        FrameError::Io(err)
+    }
+}
 
 // Native messaging protocol:
 //
-// u32 length of the JSON message
-// JSON message
-pub fn read_message<R: Read>(mut input: R) -> Result<MessageFromBrowser, MessageToError> {
-    let mut length_buffer = [0; 4];
-    input
-        .read_exact(&mut length_buffer)
-        .map_err(|err| MessageToError::IoError {
-            kind: err.kind().to_string(),
-            message: format!("{}", err),
-        })?;
-    let length = u32::from_le_bytes(length_buffer);
-
-    let mut message_buffer = vec![0; length as usize];
-    input
-        .read_exact(&mut message_buffer)
-        .map_err(|err| MessageToError::IoError {
-            kind: err.kind().to_string(),
-            message: format!("{}", err),
-        })?;
-
-    serde_json::from_slice(&message_buffer).map_err(|err| MessageToError::JsonParseError {
-        message: format!("{}", err),
-    })
-}
-
-pub fn send_message<W: Write, S: Serialize + Debug>(
-    mut output: W,
-    message: &S,
-) -> Result<(), &'static str> {
-    // log(&format!("Sending message: {:?} ", &message));
+// u32 length of the message
+// message
+//
+// The length is in native byte order in both directions. Frames longer than
+// the limit are rejected without reading them, the stream can't be trusted
+// after that.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NativeMessagingCodec {
+    pub byte_order: ByteOrder,
+    pub max_read_length: u32,
+    pub max_write_length: u32,
+}
+
+impl Default for NativeMessagingCodec {
+    fn default() -> Self {
+        NativeMessagingCodec {
+            byte_order: ByteOrder::Native,
+            max_read_length: DEFAULT_MAX_MESSAGE_FROM_BROWSER,
+            max_write_length: MAX_MESSAGE_TO_BROWSER,
+        }
+    }
+}
+
+impl NativeMessagingCodec {
+    /// Codec for the browser side of the host, reading at most `max_read_length`
+    pub fn new(max_read_length: u32) -> Self {
+        NativeMessagingCodec {
+            max_read_length,
+            ..Default::default()
+        }
+    }
+
+    pub fn encode_length(&self, length: u32) -> [u8; 4] {
+        match self.byte_order {
+            ByteOrder::Native => length.to_ne_bytes(),
+            ByteOrder::Little => length.to_le_bytes(),
+            ByteOrder::Big => length.to_be_bytes(),
+        }
+    }
+
+    pub fn decode_length(&self, bytes: [u8; 4]) -> u32 {
+        match self.byte_order {
+            ByteOrder::Native => u32::from_ne_bytes(bytes),
+            ByteOrder::Little => u32::from_le_bytes(bytes),
+            ByteOrder::Big => u32::from_be_bytes(bytes),
+        }
+    }
+
+    /// Length prefix and payload as one buffer
+    pub fn encode(&self, payload: &[u8]) -> Result<Vec<u8>, FrameError> {
+        let length = u32::try_from(payload.len()).unwrap_or(u32::MAX);
+        if length > self.max_write_length {
+            return Err(FrameError::TooLarge {
+                length,
+                max_length: self.max_write_length,
+            });
+        }
+        Ok([&self.encode_length(length)[..], payload].concat())
+    }
 
+    pub fn read_frame<R: Read>(&self, mut input: R) -> Result<Vec<u8>, FrameError> {
+        // Ending inside a frame is an error, ending before one is not
+        let mut length_buffer = [0; 4];
+        let mut read = 0;
+        while read < length_buffer.len() {
+            match input.read(&mut length_buffer[read..]) {
+                Ok(0) if read == 0 => return Err(FrameError::Closed),
+                Ok(0) => return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into()),
+                Ok(n) => read += n,
+                Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+        let length = self.decode_length(length_buffer);
+        if length > self.max_read_length {
+            return Err(FrameError::TooLarge {
+                length,
+                max_length: self.max_read_length,
+            });
+        }
+
+        // Buffer grows as the data arrives, a truncated frame doesn't
+        // allocate the whole length
+        let mut payload = Vec::new();
+        input.take(length as u64).read_to_end(&mut payload)?;
+        if payload.len() != length as usize {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
+        Ok(payload)
+    }
+
+    /// Length and payload are written at once, so that frames written from
+    /// different threads don't interleave
+    pub fn write_frame<W: Write>(&self, mut output: W, payload: &[u8]) -> Result<(), FrameError> {
+        output.write_all(&self.encode(payload)?)?;
+        output.flush()?;
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum MessageError {
+    /// Input ended between messages, e.g. the browser closed the port
+    Closed,
+    Io(std::io::Error),
+    TooLarge {
+        length: u32,
+        max_length: u32,
+    },
+    /// Message isn't valid JSON or doesn't match the expected type
+    Json {
+        id: Option<serde_json::Value>,
+        error: serde_json::Error,
+    },
+}
+
+impl MessageError {
+    /// `id` of the message the error is about, when it could be read
+    pub fn id(&self) -> Option<&serde_json::Value> {
+        match self {
+            MessageError::Json { id, .. } => id.as_ref(),
+            _ => None,
+        }
+    }
+}
+
+// Allow FrameError to be converted to MessageError
+impl From<FrameError> for MessageError {
+    fn from(err: FrameError) -> Self {
+        match err {
+            FrameError::Closed => MessageError::Closed,
+            FrameError::Io(err) => MessageError::Io(err),
+            FrameError::TooLarge { length, max_length } => {
+                MessageError::TooLarge { length, max_length }
+            }
+        }
+    }
+}
+
+/// Read a message of type `T` with its optional `id`
+pub fn read_message<T: DeserializeOwned, R: Read>(
+    input: R,
+    codec: &NativeMessagingCodec,
+) -> Result<Envelope<T>, MessageError> {
+    let message_buffer = codec.read_frame(input)?;
+
+    // Parse the `id` separately, so that it can be sent back with the error
+    let mut value: serde_json::Value = serde_json::from_slice(&message_buffer)
+        .map_err(|error| MessageError::Json { id: None, error })?;
+    let id = value.as_object_mut().and_then(|object| object.remove("id"));
+    match serde_json::from_value(value) {
+        Ok(message) => Ok(Envelope::new(id, message)),
+        Err(error) => Err(MessageError::Json { id, error }),
+    }
+}
+
+pub fn send_message<T: Serialize, W: Write>(output: W, message: &T) -> Result<(), &'static str> {
     let message_buffer =
         serde_json::to_vec(message).map_err(|_| "Send: Failed to serialize message")?;
-    let length = message_buffer.len() as u32;
-
-    // log(&format!(
-    //     "Message length: {} {} {}",
-    //     length,
-    //     length.to_le(),
-    //     length.to_be()
-    // ));
-
-    output
-        .write_all(&length.to_ne_bytes())
-        .map_err(|_| "Send: Failed to write message length")?;
-    output
-        .flush()
-        .map_err(|_| "Send: Failed to flush message")?;
-    output
-        .write_all(&message_buffer)
-        .map_err(|_| "Send: Failed to write message")?;
-
-    output
-        .flush()
-        .map_err(|_| "Send: Failed to flush message")?;
-
-    Ok(())
+
+    NativeMessagingCodec::default()
+        .write_frame(output, &message_buffer)
+        .map_err(|err| match err {
+            FrameError::TooLarge { .. } => "Send: Message is too large",
+            FrameError::Closed | FrameError::Io(_) => "Send: Failed to write message",
+        })
+}
+
+/// Native messaging host handling one request at a time
+///
+/// The handler gets each request of type `Req` and either breaks to stop, or
+/// continues with the reply, which is sent with the `id` of the request.
+/// Requests that can't be parsed are answered with `E::from(&err)`.
+///
+/// ```no_run
+/// use std::ops::ControlFlow;
+///
+/// use fbrowserhelper::native_messaging::{MessageError, NativeHost};
+/// use serde::{Deserialize, Serialize};
+///
+/// #[derive(Deserialize)]
+/// #[serde(tag = "type", rename_all = "camelCase")]
+/// enum Request {
+///     Ping,
+///     Quit,
+/// }
+///
+/// #[derive(Serialize)]
+/// #[serde(tag = "type", rename_all = "camelCase")]
+/// enum Response {
+///     Pong,
+/// }
+///
+/// #[derive(Serialize)]
+/// #[serde(tag = "type", rename_all = "camelCase")]
+/// enum Error {
+///     Invalid { message: String },
+/// }
+///
+/// impl From<&MessageError> for Error {
+///     fn from(err: &MessageError) -> Self {
+///         Error::Invalid {
+///             message: format!("{:?}", err),
+///         }
+///     }
+/// }
+///
+/// NativeHost::new(|request: Request| match request {
+///     Request::Ping => ControlFlow::Continue(Ok::<_, Error>(Response::Pong)),
+///     Request::Quit => ControlFlow::Break(()),
+/// })
+/// .run(std::io::stdin(), std::io::stdout())
+/// .unwrap();
+/// ```
+pub struct NativeHost<F> {
+    codec: NativeMessagingCodec,
+    handler: F,
+}
+
+impl<F> NativeHost<F> {
+    pub fn new(handler: F) -> Self {
+        NativeHost {
+            codec: NativeMessagingCodec::default(),
+            handler,
+        }
+    }
+
+    pub fn with_codec(mut self, codec: NativeMessagingCodec) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    /// Handle requests until the handler breaks or the input ends
+    ///
+    /// Broken frames can't be skipped, they are answered and returned.
+    pub fn run<Req, Resp, E>(
+        mut self,
+        mut input: impl Read,
+        mut output: impl Write,
+    ) -> Result<(), MessageError>
+    where
+        F: FnMut(Req) -> ControlFlow<(), Result<Resp, E>>,
+        Req: DeserializeOwned,
+        Resp: Serialize,
+        E: Serialize + for<'a> From<&'a MessageError>,
+    {
+        let send_error = |err: &'static str| {
+            MessageError::Io(std::io::Error::new(std::io::ErrorKind::BrokenPipe, err))
+        };
+
+        loop {
+            let envelope = match read_message::<Req, _>(&mut input, &self.codec) {
+                Ok(envelope) => envelope,
+                Err(MessageError::Closed) => return Ok(()),
+                Err(err) => {
+                    let reply = Envelope::new(err.id().cloned(), E::from(&err));
+                    send_message(&mut output, &reply).map_err(send_error)?;
+                    match err {
+                        MessageError::Json { .. } => continue,
+                        _ => return Err(err),
+                    }
+                }
+            };
+
+            let ControlFlow::Continue(result) = (self.handler)(envelope.message) else {
+                return Ok(());
+            };
+            match result {
+                Ok(reply) => send_message(&mut output, &Envelope::new(envelope.id, reply)),
+                Err(reply) => send_message(&mut output, &Envelope::new(envelope.id, reply)),
+            }
+            .map_err(send_error)?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    #[derive(Deserialize, Debug, PartialEq)]
+    #[serde(tag = "type", rename_all = "camelCase")]
+    enum Request {
+        Ping,
+        Add { a: i32, b: i32 },
+        Quit,
+    }
+
+    #[derive(Serialize)]
+    #[serde(tag = "type", rename_all = "camelCase")]
+    enum Response {
+        Pong,
+        Sum { sum: i32 },
+    }
+
+    #[derive(Serialize)]
+    #[serde(tag = "type", rename_all = "camelCase")]
+    enum Error {
+        Overflow,
+        Invalid { message: String },
+    }
+
+    impl From<&MessageError> for Error {
+        fn from(err: &MessageError) -> Self {
+            Error::Invalid {
+                message: format!("{:?}", err),
+            }
+        }
+    }
+
+    fn frame(json: &str) -> Vec<u8> {
+        NativeMessagingCodec::default()
+            .encode(json.as_bytes())
+            .unwrap()
+    }
+
+    fn codec(max_read_length: u32) -> NativeMessagingCodec {
+        NativeMessagingCodec::new(max_read_length)
+    }
+
+    #[test]
+    fn test_read_message_with_id() {
+        let envelope = read_message::<Request, _>(
+            &frame(r#"{"id":5,"type":"ping"}"#)[..],
+            &NativeMessagingCodec::default(),
+        )
+        .unwrap();
+        assert_eq!(envelope.id, Some(serde_json::json!(5)));
+        assert_eq!(envelope.message, Request::Ping);
+    }
+
+    #[test]
+    fn test_read_message_error_carries_id() {
+        let err = read_message::<Request, _>(
+            &frame(r#"{"id":"x","type":"unknown"}"#)[..],
+            &NativeMessagingCodec::default(),
+        )
+        .unwrap_err();
+        assert_eq!(err.id(), Some(&serde_json::json!("x")));
+        assert!(matches!(err, MessageError::Json { .. }));
+
+        let err =
+            read_message::<Request, _>(&frame("not json")[..], &NativeMessagingCodec::default())
+                .unwrap_err();
+        assert!(err.id().is_none());
+        assert!(matches!(err, MessageError::Json { .. }));
+    }
+
+    #[test]
+    fn test_read_message_too_large() {
+        let err =
+            read_message::<Request, _>(&u32::MAX.to_ne_bytes()[..], &codec(1024)).unwrap_err();
+        assert!(matches!(
+            err,
+            MessageError::TooLarge {
+                length: u32::MAX,
+                max_length: 1024
+            }
+        ));
+
+        let json = r#"{"type":"ping"}"#;
+        assert!(read_message::<Request, _>(&frame(json)[..], &codec(json.len() as u32)).is_ok());
+        assert!(
+            read_message::<Request, _>(&frame(json)[..], &codec(json.len() as u32 - 1)).is_err()
+        );
+    }
+
+    #[test]
+    fn test_read_message_closed() {
+        let err = read_message::<Request, _>(&[][..], &codec(1024)).unwrap_err();
+        assert!(matches!(err, MessageError::Closed));
+
+        // Second read after a complete frame
+        let frame = frame(r#"{"type":"ping"}"#);
+        let mut input = &frame[..];
+        assert!(read_message::<Request, _>(&mut input, &codec(1024)).is_ok());
+        let err = read_message::<Request, _>(&mut input, &codec(1024)).unwrap_err();
+        assert!(matches!(err, MessageError::Closed));
+    }
+
+    #[test]
+    fn test_read_message_truncated() {
+        let err = read_message::<Request, _>(&[1, 0][..], &codec(1024)).unwrap_err();
+        assert!(matches!(err, MessageError::Io(_)));
+
+        let frame = frame(r#"{"type":"ping"}"#);
+        let err = read_message::<Request, _>(&frame[..frame.len() - 1], &codec(1024)).unwrap_err();
+        assert!(matches!(err, MessageError::Io(_)));
+    }
+
+    #[test]
+    fn test_send_message_too_large() {
+        let mut output = Vec::new();
+        let message = "x".repeat(MAX_MESSAGE_TO_BROWSER as usize);
+        assert!(send_message(&mut output, &message).is_err());
+        assert!(output.is_empty());
+    }
+
+    #[test]
+    fn test_length_byte_order() {
+        let mut codec = NativeMessagingCodec::default();
+        assert_eq!(codec.encode_length(0x01020304), 0x01020304u32.to_ne_bytes());
+
+        codec.byte_order = ByteOrder::Little;
+        assert_eq!(codec.encode_length(0x01020304), [4, 3, 2, 1]);
+        assert_eq!(codec.decode_length([4, 3, 2, 1]), 0x01020304);
+
+        codec.byte_order = ByteOrder::Big;
+        assert_eq!(codec.encode_length(0x01020304), [1, 2, 3, 4]);
+        assert_eq!(codec.decode_length([1, 2, 3, 4]), 0x01020304);
+
+        for byte_order in [ByteOrder::Native, ByteOrder::Little, ByteOrder::Big] {
+            let codec = NativeMessagingCodec {
+                byte_order,
+                ..Default::default()
+            };
+            for length in [0, 1, 0x01020304, u32::MAX] {
+                assert_eq!(
+                    codec.decode_length(codec.encode_length(length)),
+                    length,
+                    "{:?}",
+                    byte_order
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_send_and_read_message() {
+        let mut output = Vec::new();
+        send_message(&mut output, &serde_json::json!({"id": 3, "type": "ping"})).unwrap();
+        let envelope =
+            read_message::<Request, _>(&output[..], &NativeMessagingCodec::default()).unwrap();
+        assert_eq!(envelope.id, Some(serde_json::json!(3)));
+    }
+
+    fn run_host(requests: &[&str]) -> (Result<(), MessageError>, Vec<serde_json::Value>) {
+        let input = requests
+            .iter()
+            .flat_map(|json| frame(json))
+            .collect::<Vec<_>>();
+        let mut output = Vec::new();
+        let result = NativeHost::new(|request: Request| match request {
+            Request::Ping => ControlFlow::Continue(Ok(Response::Pong)),
+            Request::Add { a, b } => ControlFlow::Continue(
+                a.checked_add(b)
+                    .map(|sum| Response::Sum { sum })
+                    .ok_or(Error::Overflow),
+            ),
+            Request::Quit => ControlFlow::Break(()),
+        })
+        .run(&input[..], &mut output);
+
+        let mut replies = Vec::new();
+        let mut output = &output[..];
+        while !output.is_empty() {
+            replies.push(read_message::<serde_json::Value, _>(&mut output, &codec(1024)).unwrap());
+        }
+        let replies = replies
+            .into_iter()
+            .map(|envelope| serde_json::to_value(envelope).unwrap())
+            .collect();
+        (result, replies)
+    }
+
+    #[test]
+    fn test_native_host() {
+        let (result, replies) = run_host(&[
+            r#"{"id":1,"type":"ping"}"#,
+            r#"{"id":2,"type":"add","a":2,"b":3}"#,
+            r#"{"id":3,"type":"add","a":2147483647,"b":1}"#,
+            r#"{"id":4,"type":"unknown"}"#,
+            r#"{"type":"quit"}"#,
+            r#"{"id":5,"type":"ping"}"#,
+        ]);
+        assert!(result.is_ok());
+        assert_eq!(replies.len(), 4);
+        assert_eq!(replies[0], serde_json::json!({"id": 1, "type": "pong"}));
+        assert_eq!(
+            replies[1],
+            serde_json::json!({"id": 2, "type": "sum", "sum": 5})
+        );
+        assert_eq!(replies[2], serde_json::json!({"id": 3, "type": "overflow"}));
+        assert_eq!(replies[3]["id"], 4);
+        assert_eq!(replies[3]["type"], "invalid");
+    }
+
+    #[test]
+    fn test_native_host_stops_at_end_of_input() {
+        let (result, replies) = run_host(&[r#"{"type":"ping"}"#]);
+        assert!(result.is_ok());
+        assert_eq!(replies, vec![serde_json::json!({"type": "pong"})]);
+    }
+
+    #[test]
+    fn test_native_host_stops_at_broken_frame() {
+        let input = u32::MAX.to_ne_bytes();
+        let mut output = Vec::new();
+        let result =
+            NativeHost::new(|_: Request| ControlFlow::<(), Result<Response, Error>>::Break(()))
+                .with_codec(codec(1024))
+                .run(&input[..], &mut output);
+        assert!(matches!(result, Err(MessageError::TooLarge { .. })));
+        assert!(!output.is_empty());
+    }
+
+    proptest! {
+        #[test]
+        fn frame_round_trip(
+            payloads in proptest::collection::vec(proptest::collection::vec(any::<u8>(), 0..64), 0..4),
+            byte_order in prop_oneof![Just(ByteOrder::Native), Just(ByteOrder::Little), Just(ByteOrder::Big)],
+        ) {
+            let codec = NativeMessagingCodec { byte_order, ..Default::default() };
+            let mut stream = Vec::new();
+            for payload in &payloads {
+                codec.write_frame(&mut stream, payload).unwrap();
+            }
+            let mut input = &stream[..];
+            for payload in &payloads {
+                prop_assert_eq!(&codec.read_frame(&mut input).unwrap(), payload);
+            }
+            prop_assert!(input.is_empty());
+        }
+
+        #[test]
+        fn read_message_never_panics(bytes in proptest::collection::vec(any::<u8>(), 0..64)) {
+            let _ = read_message::<Request, _>(&bytes[..], &codec(1024));
+        }
+
+        #[test]
+        fn read_message_rejects_truncated_frames(json in "[ -~]{0,40}", cut in any::<prop::sample::Index>()) {
+            let frame = frame(&json);
+            let cut = cut.index(frame.len() - 1) + 1;
+            let err = read_message::<Request, _>(&frame[..cut], &codec(1024)).unwrap_err();
+            prop_assert!(
+                matches!(err, MessageError::Io(_)),
+                "unexpected error {:?}",
+                err
+            );
+        }
+
+        #[test]
+        fn read_message_rejects_malformed_json(json in "[ -~]{0,40}") {
+            prop_assume!(serde_json::from_str::<serde_json::Value>(&json).is_err());
+            let err = read_message::<Request, _>(&frame(&json)[..], &codec(1024)).unwrap_err();
+            prop_assert!(
+                matches!(err, MessageError::Json { .. }),
+                "unexpected error {:?}",
+                err
+            );
+        }
+
+        #[test]
+        fn read_message_rejects_oversize_frames(length in 1025u32..) {
+            let err = read_message::<Request, _>(&length.to_ne_bytes()[..], &codec(1024)).unwrap_err();
+            prop_assert!(
+                matches!(err, MessageError::TooLarge { .. }),
+                "unexpected error {:?}",
+                err
+            );
+        }
+    }
 }
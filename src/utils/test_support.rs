@@ -0,0 +1,58 @@
+//! Fixtures shared by the tests of several modules
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+
+use url::Url;
+
+/// Local HTTP stand-in, serves given `(path, body)` pairs and 404 otherwise
+pub fn serve(routes: Vec<(&'static str, Vec<u8>)>) -> Url {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(mut stream) = stream else {
+                continue;
+            };
+            let mut request_line = String::new();
+            let mut reader = BufReader::new(&stream);
+            if reader.read_line(&mut request_line).is_err() {
+                continue;
+            }
+            // Skip the headers
+            let mut line = String::new();
+            while reader.read_line(&mut line).is_ok() && line != "\r\n" && !line.is_empty() {
+                line.clear();
+            }
+
+            let path = request_line.split(' ').nth(1).unwrap_or_default();
+            let response = match routes.iter().find(|(route, _)| *route == path) {
+                Some((_, body)) => [
+                    format!(
+                        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                        body.len()
+                    )
+                    .into_bytes(),
+                    body.clone(),
+                ]
+                .concat(),
+                None => b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                    .to_vec(),
+            };
+            let _ = stream.write_all(&response);
+        }
+    });
+    Url::parse(&format!("http://{}/", address)).unwrap()
+}
+
+/// Solid red PNG image
+pub fn png(width: u32, height: u32) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    image::RgbaImage::from_pixel(width, height, image::Rgba([255, 0, 0, 255]))
+        .write_to(
+            &mut std::io::Cursor::new(&mut bytes),
+            image::ImageFormat::Png,
+        )
+        .unwrap();
+    bytes
+}
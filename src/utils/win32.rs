@@ -1,3 +1,5 @@
+use std::collections::HashSet;
+use std::sync::Mutex;
 use std::thread;
 
 use ico::IconImage;
@@ -29,7 +31,11 @@ use windows::{
     },
 };
 
-use crate::{log, utils::favicon::get_favicon_from_url};
+use crate::utils::{
+    favicon::FaviconProviders,
+    log::{log_at, Level},
+    window_manager::{WindowManager, DEFAULT_ICON_SIZES},
+};
 
 fn main() -> windows::core::Result<()> {
     unsafe {
@@ -101,12 +107,17 @@ extern "system" fn wndproc(window: HWND, message: u32, wparam: WPARAM, lparam: L
                                 allow_maximize_and_snapping(target_window);
 
                                 // Set the icon
-                                match get_favicon_from_url(&url) {
+                                match FaviconProviders::default().get_favicon_from_url(&url, None) {
                                     Err(err) => {
                                         println!("Error {:?}", err);
                                     }
                                     Ok(icon_path) => {
-                                        set_icon(target_window, &icon_path);
+                                        set_icon(
+                                            target_window,
+                                            &icon_path,
+                                            DEFAULT_ICON_SIZES[0] as i32,
+                                            DEFAULT_ICON_SIZES[1] as i32,
+                                        );
                                         set_pinned_taskbar_icon(window, &icon_path);
                                     }
                                 }
@@ -147,17 +158,39 @@ pub fn allow_maximize_and_snapping(window: HWND) {
     unsafe { SetWindowLongA(window, GWL_STYLE, style | WS_MAXIMIZEBOX.0 as i32) };
 }
 
-pub fn set_icon(window: HWND, icon_path: &str) {
+/// Set the small and big icons of the window, loading the entries of given
+/// sizes from the ico file
+pub fn set_icon(window: HWND, icon_path: &str, small_size: i32, big_size: i32) {
     let icon_path_hstring = HSTRING::from(icon_path);
     let icon_path_pcstr = PCWSTR(icon_path_hstring.as_ptr());
-    let hicon =
-        unsafe { LoadImageW(None, icon_path_pcstr, IMAGE_ICON, 64, 64, LR_LOADFROMFILE).unwrap() };
+    let hicon = unsafe {
+        LoadImageW(
+            None,
+            icon_path_pcstr,
+            IMAGE_ICON,
+            small_size,
+            small_size,
+            LR_LOADFROMFILE,
+        )
+        .unwrap()
+    };
     let hicon2 = unsafe {
-        LoadImageW(None, icon_path_pcstr, IMAGE_ICON, 128, 128, LR_LOADFROMFILE).unwrap()
+        LoadImageW(
+            None,
+            icon_path_pcstr,
+            IMAGE_ICON,
+            big_size,
+            big_size,
+            LR_LOADFROMFILE,
+        )
+        .unwrap()
     };
 
     if hicon.is_invalid() || hicon2.is_invalid() {
-        log(&format!("Failed to load icon: {:?}", icon_path));
+        log_at(
+            Level::Warn,
+            &format!("Failed to load icon: {:?}", icon_path),
+        );
         return;
     }
 
@@ -179,6 +212,13 @@ pub fn set_icon(window: HWND, icon_path: &str) {
     };
 }
 
+/// Go back to the icons of the window class
+pub fn clear_icon(window: HWND) {
+    for size in [ICON_SMALL, ICON_BIG] {
+        let _ = unsafe { PostMessageW(window, WM_SETICON, WPARAM(size as usize), LPARAM(0)) };
+    }
+}
+
 pub fn get_active_window() -> HWND {
     unsafe { GetForegroundWindow() }
 }
@@ -211,6 +251,18 @@ pub fn ungroup_taskbar_button(window: HWND, new_id: &str) {
     }
 }
 
+/// Group the taskbar button with the other windows of the process again
+pub fn clear_taskbar_button_id(window: HWND) {
+    unsafe {
+        let store: IPropertyStore = SHGetPropertyStoreForWindow(window).unwrap();
+
+        let prop_variant = PROPVARIANT::default();
+        store
+            .SetValue(&PKEY_AppUserModel_ID, &prop_variant)
+            .unwrap();
+    }
+}
+
 pub fn prevent_pinning_taskbar_button(window: HWND) {
     unsafe {
         let store: IPropertyStore = SHGetPropertyStoreForWindow(window).unwrap();
@@ -267,7 +319,7 @@ pub fn get_process_name(window: HWND) -> String {
         let mut process_id = 0;
         GetWindowThreadProcessId(window, Some(&mut process_id as *mut u32));
         if process_id == 0 {
-            log("Failed to get process ID");
+            log_at(Level::Warn, "Failed to get process ID");
             return "".to_string();
         }
 
@@ -275,7 +327,7 @@ pub fn get_process_name(window: HWND) -> String {
         let hproc =
             OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, FALSE, process_id).unwrap_or_default();
         if hproc.0 == 0 {
-            log("Failed to get process handle");
+            log_at(Level::Warn, "Failed to get process handle");
             return "".to_string();
         }
 
@@ -286,7 +338,10 @@ pub fn get_process_name(window: HWND) -> String {
         if let Err(err) =
             QueryFullProcessImageNameW(hproc, PROCESS_NAME_FORMAT::default(), exepwstr, &mut exelen)
         {
-            log(&format!("Failed to query process name: {:?}", err));
+            log_at(
+                Level::Warn,
+                &format!("Failed to query process name: {:?}", err),
+            );
             return "".to_string();
         }
         exepwstr.to_string().unwrap_or_default()
@@ -301,6 +356,103 @@ fn get_url_from_string(string: &str) -> Option<Url> {
     }
 }
 
+/// Win32 implementation of the `WindowManager`
+pub struct Win32WindowManager {
+    small_icon_size: i32,
+    big_icon_size: i32,
+    /// Windows whose taskbar properties or icons were changed, to restore
+    /// them when exiting
+    changed_windows: Mutex<HashSet<u32>>,
+}
+
+impl Default for Win32WindowManager {
+    fn default() -> Self {
+        Win32WindowManager {
+            small_icon_size: DEFAULT_ICON_SIZES[0] as i32,
+            big_icon_size: DEFAULT_ICON_SIZES[1] as i32,
+            changed_windows: Mutex::new(HashSet::new()),
+        }
+    }
+}
+
+impl Win32WindowManager {
+    /// Use the smallest of the sizes as the small icon and the largest as the
+    /// big icon
+    pub fn with_icon_sizes(mut self, icon_sizes: &[u32]) -> Self {
+        if let (Some(&small), Some(&big)) = (icon_sizes.iter().min(), icon_sizes.iter().max()) {
+            self.small_icon_size = small as i32;
+            self.big_icon_size = big as i32;
+        }
+        self
+    }
+
+    fn changed(&self, window: u32) {
+        self.changed_windows.lock().unwrap().insert(window);
+    }
+}
+
+impl WindowManager for Win32WindowManager {
+    fn get_active_window(&self) -> u32 {
+        get_active_window().0 as u32
+    }
+
+    fn get_window_title(&self, window: u32) -> String {
+        get_window_title(HWND(window as isize))
+    }
+
+    fn get_window_class(&self, window: u32) -> String {
+        get_window_class(HWND(window as isize))
+    }
+
+    fn get_process_name(&self, window: u32) -> String {
+        get_process_name(HWND(window as isize))
+    }
+
+    fn ungroup_taskbar_button(&self, window: u32, new_id: &str) {
+        self.changed(window);
+        ungroup_taskbar_button(HWND(window as isize), new_id)
+    }
+
+    fn prevent_pinning_taskbar_button(&self, window: u32) {
+        self.changed(window);
+        prevent_pinning_taskbar_button(HWND(window as isize))
+    }
+
+    fn clear_pinned_taskbar_icon(&self, window: u32) {
+        self.changed(window);
+        clear_pinned_taskbar_icon(HWND(window as isize))
+    }
+
+    fn allow_maximize_and_snapping(&self, window: u32) {
+        allow_maximize_and_snapping(HWND(window as isize))
+    }
+
+    fn restore_windows(&self) {
+        let changed_windows = std::mem::take(&mut *self.changed_windows.lock().unwrap());
+        for window in changed_windows {
+            let window = HWND(window as isize);
+            // Closed windows have no properties to restore
+            if !unsafe { IsWindow(window) }.as_bool() {
+                continue;
+            }
+            clear_taskbar_button_id(window);
+            unprevent_pinning_taskbar_button(window);
+            clear_pinned_taskbar_icon(window);
+            clear_icon(window);
+        }
+    }
+
+    fn set_icon(&self, window: u32, icon_path: &str) {
+        self.changed(window);
+        set_icon(
+            HWND(window as isize),
+            icon_path,
+            self.small_icon_size,
+            self.big_icon_size,
+        )
+    }
+}
+
 // TODO: Pinning relaunch support:
 // PKEY_AppUserModel_RelaunchCommand to define a relaunch command when pinned
 // PKEY_AppUserModel_RelaunchDisplayNameResource name of the pinned app
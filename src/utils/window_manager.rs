@@ -0,0 +1,117 @@
+/// Sizes of the icons set by `WindowManager::set_icon`, the taskbar or panel
+/// picks the closest one
+#[cfg(windows)]
+pub const DEFAULT_ICON_SIZES: [u32; 2] = [64, 128];
+#[cfg(not(windows))]
+pub const DEFAULT_ICON_SIZES: [u32; 5] = [16, 32, 48, 64, 128];
+
+/// Window operations needed by the native messaging protocol
+///
+/// Windows are identified by the same `u32` handle that is sent to the
+/// browser in `MessageToBrowser::ActiveWindow`.
+pub trait WindowManager {
+    fn get_active_window(&self) -> u32;
+    fn get_window_title(&self, window: u32) -> String;
+    fn get_window_class(&self, window: u32) -> String;
+    fn get_process_name(&self, window: u32) -> String;
+    fn ungroup_taskbar_button(&self, window: u32, new_id: &str);
+    fn prevent_pinning_taskbar_button(&self, window: u32);
+    fn clear_pinned_taskbar_icon(&self, window: u32);
+    fn allow_maximize_and_snapping(&self, window: u32);
+    fn set_icon(&self, window: u32, icon_path: &str);
+
+    /// Undo the changes made to windows that still exist, called on shutdown
+    fn restore_windows(&self) {}
+}
+
+#[cfg(test)]
+pub mod fake {
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    use super::WindowManager;
+
+    /// Call recorded by the `FakeWindowManager`
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub enum Call {
+        GetActiveWindow,
+        GetWindowTitle(u32),
+        GetWindowClass(u32),
+        GetProcessName(u32),
+        UngroupTaskbarButton(u32, String),
+        PreventPinningTaskbarButton(u32),
+        ClearPinnedTaskbarIcon(u32),
+        AllowMaximizeAndSnapping(u32),
+        SetIcon(u32, String),
+        RestoreWindows,
+    }
+
+    /// In-memory window manager that records every call
+    #[derive(Debug, Default)]
+    pub struct FakeWindowManager {
+        pub active_window: u32,
+        pub title: String,
+        pub class_name: String,
+        pub process_name: String,
+        /// Time ungrouping takes, like a slow window system
+        pub delay: Duration,
+        pub calls: Mutex<Vec<Call>>,
+    }
+
+    impl FakeWindowManager {
+        pub fn calls(&self) -> Vec<Call> {
+            self.calls.lock().unwrap().clone()
+        }
+
+        fn record(&self, call: Call) {
+            self.calls.lock().unwrap().push(call);
+        }
+    }
+
+    impl WindowManager for FakeWindowManager {
+        fn get_active_window(&self) -> u32 {
+            self.record(Call::GetActiveWindow);
+            self.active_window
+        }
+
+        fn get_window_title(&self, window: u32) -> String {
+            self.record(Call::GetWindowTitle(window));
+            self.title.clone()
+        }
+
+        fn get_window_class(&self, window: u32) -> String {
+            self.record(Call::GetWindowClass(window));
+            self.class_name.clone()
+        }
+
+        fn get_process_name(&self, window: u32) -> String {
+            self.record(Call::GetProcessName(window));
+            self.process_name.clone()
+        }
+
+        fn ungroup_taskbar_button(&self, window: u32, new_id: &str) {
+            self.record(Call::UngroupTaskbarButton(window, new_id.into()));
+            std::thread::sleep(self.delay);
+        }
+
+        fn prevent_pinning_taskbar_button(&self, window: u32) {
+            self.record(Call::PreventPinningTaskbarButton(window));
+        }
+
+        fn clear_pinned_taskbar_icon(&self, window: u32) {
+            self.record(Call::ClearPinnedTaskbarIcon(window));
+        }
+
+        fn allow_maximize_and_snapping(&self, window: u32) {
+            self.record(Call::AllowMaximizeAndSnapping(window));
+        }
+
+        fn set_icon(&self, window: u32, icon_path: &str) {
+            self.record(Call::SetIcon(window, icon_path.into()));
+        }
+
+        fn restore_windows(&self) {
+            self.record(Call::RestoreWindows);
+        }
+    }
+}
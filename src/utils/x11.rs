@@ -0,0 +1,521 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use image::RgbaImage;
+use x11rb::{
+    connection::Connection,
+    protocol::xproto::{AtomEnum, ConnectionExt, GetPropertyReply, PropMode, Window},
+    rust_connection::RustConnection,
+    wrapper::ConnectionExt as _,
+};
+
+use crate::utils::{
+    favicon::resize_icon,
+    log::{log_at, Level},
+    window_manager::{WindowManager, DEFAULT_ICON_SIZES},
+};
+
+x11rb::atom_manager! {
+    pub Atoms: AtomsCookie {
+        _NET_ACTIVE_WINDOW,
+        _NET_WM_ICON,
+        _NET_WM_NAME,
+        _NET_WM_PID,
+        UTF8_STRING,
+    }
+}
+
+/// X11 implementation of the `WindowManager`
+///
+/// Reads the EWMH properties set by the window manager and the browsers.
+pub struct X11WindowManager {
+    conn: RustConnection,
+    root: Window,
+    atoms: Atoms,
+    /// `WM_CLASS` values before ungrouping, keyed by window
+    original_wm_classes: Mutex<HashMap<Window, (String, String)>>,
+    /// Icon sizes written to `_NET_WM_ICON`
+    icon_sizes: Vec<u32>,
+}
+
+impl X11WindowManager {
+    /// Connect to the display given in the `DISPLAY` environment variable
+    pub fn connect() -> Result<Self, &'static str> {
+        let (conn, screen_num) =
+            x11rb::connect(None).map_err(|_| "Failed to connect to the X11 display")?;
+        let root = conn.setup().roots[screen_num].root;
+        let atoms = Atoms::new(&conn)
+            .map_err(|_| "Failed to intern X11 atoms")?
+            .reply()
+            .map_err(|_| "Failed to intern X11 atoms")?;
+        Ok(X11WindowManager {
+            conn,
+            root,
+            atoms,
+            original_wm_classes: Mutex::new(HashMap::new()),
+            icon_sizes: DEFAULT_ICON_SIZES.to_vec(),
+        })
+    }
+
+    pub fn with_icon_sizes(mut self, icon_sizes: &[u32]) -> Self {
+        self.icon_sizes = icon_sizes.to_vec();
+        self
+    }
+
+    fn set_wm_class(
+        &self,
+        window: Window,
+        instance: &str,
+        class: &str,
+    ) -> Result<(), x11rb::errors::ConnectionError> {
+        self.conn.change_property8(
+            PropMode::REPLACE,
+            window,
+            AtomEnum::WM_CLASS,
+            AtomEnum::STRING,
+            format!("{}\0{}\0", instance, class).as_bytes(),
+        )?;
+        self.conn.flush()
+    }
+
+    fn get_property(
+        &self,
+        window: Window,
+        property: impl Into<u32>,
+        type_: impl Into<u32>,
+    ) -> Option<GetPropertyReply> {
+        let reply = self
+            .conn
+            .get_property(false, window, property, type_, 0, u32::MAX)
+            .ok()?
+            .reply();
+        match reply {
+            Ok(reply) if reply.format != 0 => Some(reply),
+            Ok(_) => None,
+            Err(err) => {
+                log_at(
+                    Level::Warn,
+                    &format!("Failed to get property of window {}: {:?}", window, err),
+                );
+                None
+            }
+        }
+    }
+}
+
+impl WindowManager for X11WindowManager {
+    fn get_active_window(&self) -> u32 {
+        self.get_property(self.root, self.atoms._NET_ACTIVE_WINDOW, AtomEnum::WINDOW)
+            .and_then(|reply| reply.value32()?.next())
+            .unwrap_or_default()
+    }
+
+    fn get_window_title(&self, window: u32) -> String {
+        if let Some(reply) =
+            self.get_property(window, self.atoms._NET_WM_NAME, self.atoms.UTF8_STRING)
+        {
+            return String::from_utf8_lossy(&reply.value).into_owned();
+        }
+
+        // Fallback to ICCCM title, which is usually Latin-1
+        self.get_property(window, AtomEnum::WM_NAME, AtomEnum::STRING)
+            .map(|reply| reply.value.iter().map(|&c| c as char).collect())
+            .unwrap_or_default()
+    }
+
+    fn get_window_class(&self, window: u32) -> String {
+        self.get_property(window, AtomEnum::WM_CLASS, AtomEnum::STRING)
+            .map(|reply| parse_wm_class(&reply.value).1)
+            .unwrap_or_default()
+    }
+
+    fn get_process_name(&self, window: u32) -> String {
+        let Some(pid) = self
+            .get_property(window, self.atoms._NET_WM_PID, AtomEnum::CARDINAL)
+            .and_then(|reply| reply.value32()?.next())
+        else {
+            log_at(Level::Warn, "Failed to get process ID");
+            return "".to_string();
+        };
+
+        match std::fs::read_link(format!("/proc/{}/exe", pid)) {
+            Ok(exe) => exe.to_string_lossy().into_owned(),
+            Err(err) => {
+                log_at(
+                    Level::Warn,
+                    &format!("Failed to query process name: {:?}", err),
+                );
+                "".to_string()
+            }
+        }
+    }
+
+    fn ungroup_taskbar_button(&self, window: u32, new_id: &str) {
+        // Docks group windows by WM_CLASS, derive the new class always from the
+        // original so that ungrouping twice does not stack the suffixes
+        let (_, original_class) = self
+            .original_wm_classes
+            .lock()
+            .unwrap()
+            .entry(window)
+            .or_insert_with(|| {
+                self.get_property(window, AtomEnum::WM_CLASS, AtomEnum::STRING)
+                    .map(|reply| parse_wm_class(&reply.value))
+                    .unwrap_or_default()
+            })
+            .clone();
+        let (instance, class) = ungrouped_wm_class(&original_class, new_id);
+
+        if let Err(err) = self.set_wm_class(window, &instance, &class) {
+            log_at(
+                Level::Warn,
+                &format!("Failed to ungroup window {}: {:?}", window, err),
+            );
+        }
+    }
+
+    // Pinning and maximize box are Windows taskbar concepts, nothing to do
+    fn prevent_pinning_taskbar_button(&self, _window: u32) {}
+
+    fn clear_pinned_taskbar_icon(&self, _window: u32) {}
+
+    fn allow_maximize_and_snapping(&self, _window: u32) {}
+
+    fn restore_windows(&self) {
+        let original_wm_classes = std::mem::take(&mut *self.original_wm_classes.lock().unwrap());
+        for (window, (instance, class)) in original_wm_classes {
+            // Closed windows fail asynchronously, those errors are not read
+            if let Err(err) = self.set_wm_class(window, &instance, &class) {
+                log_at(
+                    Level::Warn,
+                    &format!("Failed to restore window {}: {:?}", window, err),
+                );
+            }
+        }
+    }
+
+    fn set_icon(&self, window: u32, icon_path: &str) {
+        let images = match load_icon_sizes(icon_path, &self.icon_sizes) {
+            Ok(images) => images,
+            Err(err) => {
+                log_at(
+                    Level::Warn,
+                    &format!("Failed to load icon: {:?} {:?}", icon_path, err),
+                );
+                return;
+            }
+        };
+
+        let result = self
+            .conn
+            .change_property32(
+                PropMode::REPLACE,
+                window,
+                self.atoms._NET_WM_ICON,
+                AtomEnum::CARDINAL,
+                &net_wm_icon_data(&images),
+            )
+            .and_then(|_| self.conn.flush());
+        if let Err(err) = result {
+            log_at(
+                Level::Warn,
+                &format!("Failed to set icon of window {}: {:?}", window, err),
+            );
+        }
+    }
+}
+
+/// Split `WM_CLASS` value into instance and class names
+///
+/// The value is two null terminated strings, e.g. `Navigator\0firefox\0`
+fn parse_wm_class(value: &[u8]) -> (String, String) {
+    let mut parts = value
+        .split(|&c| c == 0)
+        .map(|part| String::from_utf8_lossy(part).into_owned());
+    let instance = parts.next().unwrap_or_default();
+    let class = parts.next().unwrap_or_default();
+    (instance, class)
+}
+
+/// Instance and class names for an ungrouped window
+///
+/// The `new_id` is appended to the original class, e.g. `firefox` with id
+/// `12` becomes `firefox-12`. Characters other than ASCII alphanumerics, `-`,
+/// `_` and `.` are replaced with `_`.
+fn ungrouped_wm_class(original_class: &str, new_id: &str) -> (String, String) {
+    let base = if original_class.is_empty() {
+        "fbrowserhelper"
+    } else {
+        original_class
+    };
+    let new_id = new_id
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' | '.' => c,
+            _ => '_',
+        })
+        .collect::<String>();
+    let class = format!("{}-{}", base, new_id);
+    (class.to_lowercase(), class)
+}
+
+/// Load the images of given sizes from an ico file
+///
+/// Entries of matching size are used as is, others are scaled from the
+/// largest entry.
+fn load_icon_sizes(icon_path: &str, sizes: &[u32]) -> Result<Vec<RgbaImage>, std::io::Error> {
+    let icon_dir = ico::IconDir::read(std::fs::File::open(icon_path)?)?;
+    let mut entries = icon_dir
+        .entries()
+        .iter()
+        .map(|entry| {
+            let icon = entry.decode()?;
+            RgbaImage::from_raw(icon.width(), icon.height(), icon.rgba_data().to_vec())
+                .ok_or_else(|| std::io::Error::other("Invalid icon entry"))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|image| image.width());
+    let largest = entries
+        .last()
+        .ok_or_else(|| std::io::Error::other("Icon has no entries"))?;
+
+    Ok(sizes
+        .iter()
+        .map(|&size| {
+            entries
+                .iter()
+                .find(|image| image.dimensions() == (size, size))
+                .cloned()
+                .unwrap_or_else(|| resize_icon(largest, size))
+        })
+        .collect())
+}
+
+/// Encode images in the `_NET_WM_ICON` format
+///
+/// Each image is its width and height followed by the pixels as ARGB
+/// cardinals, row by row.
+fn net_wm_icon_data(images: &[RgbaImage]) -> Vec<u32> {
+    let mut data = Vec::new();
+    for image in images {
+        data.push(image.width());
+        data.push(image.height());
+        data.extend(image.pixels().map(|pixel| {
+            let [r, g, b, a] = pixel.0;
+            u32::from_be_bytes([a, r, g, b])
+        }));
+    }
+    data
+}
+
+// Tests talking to the X server are ignored, run them with
+// `xvfb-run cargo test -- --ignored`
+#[cfg(test)]
+mod tests {
+    use image::Rgba;
+    use x11rb::{
+        protocol::xproto::{CreateWindowAux, WindowClass},
+        COPY_DEPTH_FROM_PARENT,
+    };
+
+    use super::*;
+
+    fn create_window(wm: &X11WindowManager) -> Window {
+        let window = wm.conn.generate_id().unwrap();
+        wm.conn
+            .create_window(
+                COPY_DEPTH_FROM_PARENT,
+                window,
+                wm.root,
+                0,
+                0,
+                100,
+                100,
+                0,
+                WindowClass::INPUT_OUTPUT,
+                0,
+                &CreateWindowAux::new(),
+            )
+            .unwrap();
+        window
+    }
+
+    #[test]
+    fn test_load_icon_sizes() {
+        let dir = tempfile::tempdir().unwrap();
+        let icon_path = dir.path().join("icon.ico");
+        let mut icon_dir = ico::IconDir::new(ico::ResourceType::Icon);
+        for (size, color) in [(32, [0, 255, 0, 255]), (256, [255, 0, 0, 255])] {
+            let icon =
+                ico::IconImage::from_rgba_data(size, size, color.repeat((size * size) as usize));
+            icon_dir.add_entry(ico::IconDirEntry::encode(&icon).unwrap());
+        }
+        icon_dir
+            .write(std::fs::File::create(&icon_path).unwrap())
+            .unwrap();
+
+        let images = load_icon_sizes(icon_path.to_str().unwrap(), &DEFAULT_ICON_SIZES).unwrap();
+        let sizes = images.iter().map(|image| image.width()).collect::<Vec<_>>();
+        assert_eq!(sizes, DEFAULT_ICON_SIZES);
+        assert_eq!(images[0].get_pixel(0, 0).0, [255, 0, 0, 255]);
+        assert_eq!(images[1].get_pixel(0, 0).0, [0, 255, 0, 255]);
+    }
+
+    #[test]
+    fn test_net_wm_icon_data() {
+        let mut image = RgbaImage::new(2, 1);
+        image.put_pixel(0, 0, Rgba([0x11, 0x22, 0x33, 0xff]));
+        image.put_pixel(1, 0, Rgba([0xaa, 0xbb, 0xcc, 0x00]));
+        let small = RgbaImage::from_pixel(1, 1, Rgba([1, 2, 3, 4]));
+
+        assert_eq!(
+            net_wm_icon_data(&[image, small]),
+            vec![2, 1, 0xff112233, 0x00aabbcc, 1, 1, 0x04010203]
+        );
+    }
+
+    #[test]
+    fn test_ungrouped_wm_class() {
+        assert_eq!(
+            ungrouped_wm_class("Chromium", "12"),
+            ("chromium-12".into(), "Chromium-12".into())
+        );
+        assert_eq!(
+            ungrouped_wm_class("firefox", "https://example.com/"),
+            (
+                "firefox-https___example.com_".into(),
+                "firefox-https___example.com_".into()
+            )
+        );
+        assert_eq!(
+            ungrouped_wm_class("", "7"),
+            ("fbrowserhelper-7".into(), "fbrowserhelper-7".into())
+        );
+    }
+
+    #[test]
+    fn test_parse_wm_class() {
+        assert_eq!(
+            parse_wm_class(b"Navigator\0firefox\0"),
+            ("Navigator".into(), "firefox".into())
+        );
+        assert_eq!(
+            parse_wm_class(b"chromium\0"),
+            ("chromium".into(), "".into())
+        );
+        assert_eq!(parse_wm_class(b""), ("".into(), "".into()));
+    }
+
+    #[test]
+    #[ignore = "needs Xvfb"]
+    fn test_active_window() {
+        let wm = X11WindowManager::connect().unwrap();
+        let conn = &wm.conn;
+        let window = create_window(&wm);
+        conn.change_property8(
+            PropMode::REPLACE,
+            window,
+            AtomEnum::WM_CLASS,
+            AtomEnum::STRING,
+            b"Navigator\0firefox\0",
+        )
+        .unwrap();
+        conn.change_property8(
+            PropMode::REPLACE,
+            window,
+            wm.atoms._NET_WM_NAME,
+            wm.atoms.UTF8_STRING,
+            "Example — Mozilla Firefox".as_bytes(),
+        )
+        .unwrap();
+        conn.change_property32(
+            PropMode::REPLACE,
+            window,
+            wm.atoms._NET_WM_PID,
+            AtomEnum::CARDINAL,
+            &[std::process::id()],
+        )
+        .unwrap();
+
+        // Without a window manager the active window must be set by hand
+        conn.change_property32(
+            PropMode::REPLACE,
+            wm.root,
+            wm.atoms._NET_ACTIVE_WINDOW,
+            AtomEnum::WINDOW,
+            &[window],
+        )
+        .unwrap();
+        conn.sync().unwrap();
+
+        assert_eq!(wm.get_active_window(), window);
+        assert_eq!(wm.get_window_class(window), "firefox");
+        assert_eq!(wm.get_window_title(window), "Example — Mozilla Firefox");
+        assert_eq!(
+            wm.get_process_name(window),
+            std::env::current_exe().unwrap().to_string_lossy()
+        );
+    }
+
+    #[test]
+    #[ignore = "needs Xvfb"]
+    fn test_set_icon() {
+        let wm = X11WindowManager::connect().unwrap();
+        let window = create_window(&wm);
+
+        let icon_path = std::env::temp_dir().join("fbrowserhelper_test_set_icon.ico");
+        let icon = ico::IconImage::from_rgba_data(256, 256, [255, 0, 0, 255].repeat(256 * 256));
+        let mut icon_dir = ico::IconDir::new(ico::ResourceType::Icon);
+        icon_dir.add_entry(ico::IconDirEntry::encode(&icon).unwrap());
+        icon_dir
+            .write(std::fs::File::create(&icon_path).unwrap())
+            .unwrap();
+        wm.set_icon(window, icon_path.to_str().unwrap());
+        wm.conn.sync().unwrap();
+
+        let data = wm
+            .get_property(window, wm.atoms._NET_WM_ICON, AtomEnum::CARDINAL)
+            .unwrap()
+            .value32()
+            .unwrap()
+            .collect::<Vec<_>>();
+        let expected_len = DEFAULT_ICON_SIZES.iter().map(|s| 2 + s * s).sum::<u32>();
+        assert_eq!(data.len(), expected_len as usize);
+        assert_eq!(&data[..3], &[16, 16, 0xffff0000]);
+    }
+
+    // Result can be also inspected with `xprop WM_CLASS`
+    #[test]
+    #[ignore = "needs Xvfb"]
+    fn test_ungroup_taskbar_button() {
+        let wm = X11WindowManager::connect().unwrap();
+        let window = create_window(&wm);
+        wm.conn
+            .change_property8(
+                PropMode::REPLACE,
+                window,
+                AtomEnum::WM_CLASS,
+                AtomEnum::STRING,
+                b"Navigator\0firefox\0",
+            )
+            .unwrap();
+        wm.conn.sync().unwrap();
+
+        wm.ungroup_taskbar_button(window, "12");
+        wm.ungroup_taskbar_button(window, "12");
+        wm.conn.sync().unwrap();
+
+        let value = wm
+            .get_property(window, AtomEnum::WM_CLASS, AtomEnum::STRING)
+            .unwrap()
+            .value;
+        assert_eq!(value, b"firefox-12\0firefox-12\0");
+
+        wm.restore_windows();
+        wm.conn.sync().unwrap();
+        let value = wm
+            .get_property(window, AtomEnum::WM_CLASS, AtomEnum::STRING)
+            .unwrap()
+            .value;
+        assert_eq!(value, b"Navigator\0firefox\0");
+    }
+}